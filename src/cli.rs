@@ -0,0 +1,176 @@
+use std::env;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "usage: mal [OPTIONS] [FILE | -] [ARGS...]
+
+Runs FILE (or stdin for -) as a script, or starts the REPL when there is
+nothing to run. Any ARGS are available to the script as *ARGV*.
+
+options:
+  -e, --eval EXPR         evaluate EXPR and print its result, may be repeated
+  -l, --load FILE         load FILE before anything else, may be repeated
+  -r, --repl              start the REPL after running the script or -e
+      --no-history        don't read or write the REPL history
+      --history-file PATH keep the REPL history in PATH (default $MAL_HISTORY,
+                          or ~/.local/share/mal/history)
+      --init-file PATH    load PATH before the REPL starts (default ~/.malrc)
+      --no-color          don't highlight REPL input and output (also set by
+                          $NO_COLOR, and off when stdout isn't a terminal)
+  -q, --quiet             don't print REPL status messages
+  -v, --version           print the version and exit
+  -h, --help              print this help and exit";
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub evals: Vec<String>,
+    pub loads: Vec<String>,
+    pub script: Option<String>,
+    pub argv: Vec<String>,
+    pub repl: bool,
+    pub history: bool,
+    pub history_file: Option<String>,
+    pub init_file: Option<String>,
+    pub color: bool,
+    pub quiet: bool,
+    pub help: bool,
+    pub version: bool,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            evals: Vec::new(),
+            loads: Vec::new(),
+            script: None,
+            argv: Vec::new(),
+            repl: false,
+            history: true,
+            history_file: None,
+            init_file: None,
+            color: true,
+            quiet: false,
+            help: false,
+            version: false,
+        }
+    }
+
+    //The REPL runs when asked for, or when there is no script or expression to run
+    pub fn interactive(&self) -> bool {
+        self.repl || (self.script.is_none() && self.evals.is_empty())
+    }
+
+    //An explicit --init-file, otherwise ~/.malrc when it exists
+    pub fn init_file_path(&self) -> Option<PathBuf> {
+        match self.init_file {
+            Some(ref f) => Some(PathBuf::from(f)),
+            None => env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".malrc"))
+                .filter(|p| p.is_file()),
+        }
+    }
+}
+
+fn option_value(args: &[String], i: usize) -> Result<String, String> {
+    match args.get(i + 1) {
+        Some(v) => Ok(v.clone()),
+        None => Err(format!("{} requires a value", args[i])),
+    }
+}
+
+//Parses the arguments after the program name
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        match args[i].as_str() {
+            "-e" | "--eval" => {
+                opts.evals.push(option_value(args, i)?);
+                i += 1;
+            }
+            "-l" | "--load" => {
+                opts.loads.push(option_value(args, i)?);
+                i += 1;
+            }
+            "--history-file" => {
+                opts.history_file = Some(option_value(args, i)?);
+                i += 1;
+            }
+            "--init-file" => {
+                opts.init_file = Some(option_value(args, i)?);
+                i += 1;
+            }
+            "-r" | "--repl" => opts.repl = true,
+            "--no-history" => opts.history = false,
+            "--no-color" => opts.color = false,
+            "-q" | "--quiet" => opts.quiet = true,
+            "-v" | "--version" => opts.version = true,
+            "-h" | "--help" => opts.help = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            a if a.starts_with('-') => return Err(format!("unknown option {}", a)),
+            _ => break,
+        }
+        i += 1;
+    }
+
+    if i < args.len() {
+        opts.script = Some(args[i].clone());
+        opts.argv = args[i + 1..].to_vec();
+    }
+
+    Ok(opts)
+}
+
+/*
+  Unit Tests for various functions/methods
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_args_test() {
+        let opts = parse_args(&args(&[])).unwrap();
+        assert_eq!(Options::new(), opts);
+        assert!(opts.interactive());
+
+        let opts = parse_args(&args(&["script.mal", "-e", "x"])).unwrap();
+        assert_eq!(Some("script.mal".to_string()), opts.script);
+        assert_eq!(args(&["-e", "x"]), opts.argv);
+        assert!(opts.evals.is_empty());
+        assert!(!opts.interactive());
+
+        let opts = parse_args(&args(&[
+            "-l", "a.mal", "--load", "b.mal", "-e", "(+ 1 2)", "--repl", "-q",
+            "--no-history", "--history-file", "h.txt", "--init-file", "init.mal", "--no-color",
+        ])).unwrap();
+        assert_eq!(args(&["a.mal", "b.mal"]), opts.loads);
+        assert_eq!(args(&["(+ 1 2)"]), opts.evals);
+        assert_eq!(None, opts.script);
+        assert!(opts.repl && opts.quiet && !opts.history && !opts.color);
+        assert!(opts.interactive());
+        assert_eq!(Some("h.txt".to_string()), opts.history_file);
+        assert_eq!(Some(PathBuf::from("init.mal")), opts.init_file_path());
+
+        let opts = parse_args(&args(&["-", "a"])).unwrap();
+        assert_eq!(Some("-".to_string()), opts.script);
+        assert_eq!(args(&["a"]), opts.argv);
+
+        let opts = parse_args(&args(&["--", "--help"])).unwrap();
+        assert_eq!(Some("--help".to_string()), opts.script);
+        assert!(!opts.help);
+
+        assert!(parse_args(&args(&["-v"])).unwrap().version);
+        assert!(parse_args(&args(&["-h"])).unwrap().help);
+        assert!(parse_args(&args(&["--eval"])).is_err());
+        assert!(parse_args(&args(&["--bogus"])).is_err());
+    }
+}
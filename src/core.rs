@@ -1,10 +1,27 @@
+use eval;
 use eval::Environment;
 use printer;
+use reader::read_all;
 use reader::read_str;
+use regex::Captures;
+use regex::Regex;
 use rep;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use types::compile_regex;
 use types::BuiltinFunc;
 use types::BuiltinFuncArgs;
+use types::HandleStream;
+use types::MalHandle;
 use types::MalType;
 
 pub fn create_namespace() -> Vec<(&'static str, Rc<Box<BuiltinFunc>>)> {
@@ -29,6 +46,30 @@ pub fn create_namespace() -> Vec<(&'static str, Rc<Box<BuiltinFunc>>)> {
     ns.push((">=", Rc::new(Box::new(ge_builtin))));
     ns.push(("read-string", Rc::new(Box::new(read_string_builtin))));
     ns.push(("slurp", Rc::new(Box::new(slurp_builtin))));
+    ns.push(("spit", Rc::new(Box::new(spit_builtin))));
+    ns.push(("file-exists?", Rc::new(Box::new(file_exists_builtin))));
+    ns.push(("list-dir", Rc::new(Box::new(list_dir_builtin))));
+    ns.push(("mkdir", Rc::new(Box::new(mkdir_builtin))));
+    ns.push(("delete-file", Rc::new(Box::new(delete_file_builtin))));
+    ns.push(("rename-file", Rc::new(Box::new(rename_file_builtin))));
+    ns.push(("file-info", Rc::new(Box::new(file_info_builtin))));
+    ns.push(("read-lines", Rc::new(Box::new(read_lines_builtin))));
+    ns.push(("open", Rc::new(Box::new(open_builtin))));
+    ns.push(("handle?", Rc::new(Box::new(handle_test_builtin))));
+    ns.push(("read-line", Rc::new(Box::new(read_line_builtin))));
+    ns.push(("read-char", Rc::new(Box::new(read_char_builtin))));
+    ns.push(("write", Rc::new(Box::new(write_builtin))));
+    ns.push(("flush", Rc::new(Box::new(flush_builtin))));
+    ns.push(("close", Rc::new(Box::new(close_builtin))));
+    ns.push(("sh", Rc::new(Box::new(sh_builtin))));
+    ns.push(("sh-stream", Rc::new(Box::new(sh_stream_builtin))));
+    ns.push(("getenv", Rc::new(Box::new(getenv_builtin))));
+    ns.push(("setenv", Rc::new(Box::new(setenv_builtin))));
+    ns.push(("env-map", Rc::new(Box::new(env_map_builtin))));
+    ns.push(("exit", Rc::new(Box::new(exit_builtin))));
+    ns.push(("cwd", Rc::new(Box::new(cwd_builtin))));
+    ns.push(("hostname", Rc::new(Box::new(hostname_builtin))));
+    ns.push(("pid", Rc::new(Box::new(pid_builtin))));
     ns.push(("atom", Rc::new(Box::new(atom_builtin))));
     ns.push(("atom?", Rc::new(Box::new(atom_test_builtin))));
     ns.push(("deref", Rc::new(Box::new(deref_builtin))));
@@ -39,6 +80,21 @@ pub fn create_namespace() -> Vec<(&'static str, Rc<Box<BuiltinFunc>>)> {
     ns.push(("nth", Rc::new(Box::new(nth_builtin))));
     ns.push(("first", Rc::new(Box::new(first_builtin))));
     ns.push(("rest", Rc::new(Box::new(rest_builtin))));
+    ns.push(("char", Rc::new(Box::new(char_builtin))));
+    ns.push(("char?", Rc::new(Box::new(char_test_builtin))));
+    ns.push(("int", Rc::new(Box::new(int_builtin))));
+    ns.push(("seq", Rc::new(Box::new(seq_builtin))));
+    ns.push(("vec", Rc::new(Box::new(vec_builtin))));
+    ns.push(("hash-map", Rc::new(Box::new(hash_map_builtin))));
+    ns.push(("gensym", Rc::new(Box::new(gensym_builtin))));
+    ns.push(("re-pattern", Rc::new(Box::new(re_pattern_builtin))));
+    ns.push(("regex?", Rc::new(Box::new(regex_test_builtin))));
+    ns.push(("re-find", Rc::new(Box::new(re_find_builtin))));
+    ns.push(("re-matches", Rc::new(Box::new(re_matches_builtin))));
+    ns.push(("re-seq", Rc::new(Box::new(re_seq_builtin))));
+    ns.push(("re-groups", Rc::new(Box::new(re_groups_builtin))));
+    ns.push(("replace", Rc::new(Box::new(replace_builtin))));
+    ns.push(("split", Rc::new(Box::new(split_builtin))));
 
     ns
 }
@@ -49,14 +105,108 @@ pub fn init_environment(env: &mut Environment) {
     }
 
     env.set("*ARGV*", MalType::list(Vec::new()));
+    env.set("*file*", MalType::nil());
+    env.set_dynamic("*print-width*", MalType::int(printer::DEFAULT_WIDTH as i64));
+    env.set_dynamic("*print-align-maps*", MalType::bool(false));
+    env.set_dynamic("*print-length*", MalType::nil());
+    env.set_dynamic("*print-level*", MalType::nil());
+    env.set("*max-depth*", MalType::int(eval::DEFAULT_MAX_DEPTH as i64));
+    env.set_dynamic("*trace-macros*", MalType::bool(false));
+    printer::set_settings_env(env);
+    env.set(
+        "*in*",
+        MalType::handle(MalHandle::input(
+            "*in*".to_string(),
+            Box::new(io::BufReader::new(io::stdin())),
+        )),
+    );
+    env.set(
+        "*out*",
+        MalType::handle(MalHandle::output("*out*".to_string(), Box::new(io::stdout()))),
+    );
+    env.set(
+        "*err*",
+        MalType::handle(MalHandle::output("*err*".to_string(), Box::new(io::stderr()))),
+    );
 
-    rep("(def! not (fn* (a) (if a false true)))", env);
-    rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))",
-        env,
+    let root = env.clone();
+    env.set(
+        "load-file",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                load_file_builtin(&root, args)
+            })),
+            false,
+        ),
+    );
+
+    env.set("*ns*", MalType::symbol(eval::DEFAULT_NS.to_string()));
+    let mal_path = env::var_os("MAL_PATH").map_or(Vec::new(), |p| {
+        env::split_paths(&p)
+            .map(|dir| MalType::string(dir.to_string_lossy().into_owned()))
+            .collect()
+    });
+    env.set("*load-path*", MalType::vector(mal_path));
+    let root = env.clone();
+    env.set(
+        "in-ns",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                in_ns_builtin(&root, args)
+            })),
+            false,
+        ),
+    );
+
+    env.set("ns", MalType::func(Rc::new(Box::new(ns_macro)), true));
+
+    let root = env.clone();
+    env.set(
+        "require",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                require_builtin(&root, args)
+            })),
+            false,
+        ),
+    );
+
+    let root = env.clone();
+    env.set(
+        "refer",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                refer_builtin(&root, args)
+            })),
+            false,
+        ),
+    );
+
+    let root = env.clone();
+    env.set(
+        "alias",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                alias_builtin(&root, args)
+            })),
+            false,
+        ),
     );
+
+    let root = env.clone();
+    env.set(
+        "pprint",
+        MalType::func(
+            Rc::new(Box::new(move |args: BuiltinFuncArgs| {
+                pprint_builtin(&root, args)
+            })),
+            false,
+        ),
+    );
+
+    rep("(def! not (fn* (a) (if a false true)))", env);
     rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", env);
-    rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))", env);
+    rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or# ~(first xs)) (if or# or# (or ~@(rest xs))))))))", env);
 }
 
 fn all_numeric(args: &BuiltinFuncArgs) -> bool {
@@ -67,7 +217,13 @@ fn all_int(args: &BuiltinFuncArgs) -> bool {
     args.iter().all(|i| i.is_int())
 }
 
-fn prn_helper(args: BuiltinFuncArgs, print_readably: bool, delimiter: &str) -> String {
+//Limited output honours *print-length* and *print-level*, for showing values rather than building data
+fn prn_helper(
+    args: BuiltinFuncArgs,
+    print_readably: bool,
+    delimiter: &str,
+    limited: bool,
+) -> String {
     let mut result = String::new();
 
     for (i, t) in args.iter().enumerate() {
@@ -75,7 +231,11 @@ fn prn_helper(args: BuiltinFuncArgs, print_readably: bool, delimiter: &str) -> S
         if i > 0 {
             result.push_str(&delimiter);
         }
-        result.push_str(&printer::pr_str(&t, print_readably));
+        if limited {
+            result.push_str(&printer::pr_str_limited(&t, print_readably));
+        } else {
+            result.push_str(&printer::pr_str(&t, print_readably));
+        }
         //println!("{}", result);
     }
 
@@ -83,23 +243,48 @@ fn prn_helper(args: BuiltinFuncArgs, print_readably: bool, delimiter: &str) -> S
 }
 
 fn prn_builtin(args: BuiltinFuncArgs) -> MalType {
-    println!("{}", prn_helper(args, true, " "));
+    println!("{}", prn_helper(args, true, " ", true));
 
     MalType::nil()
 }
 
 fn println_builtin(args: BuiltinFuncArgs) -> MalType {
-    println!("{}", prn_helper(args, false, " "));
+    println!("{}", prn_helper(args, false, " ", true));
+
+    MalType::nil()
+}
+
+//Pretty printing settings from *print-width* and *print-align-maps*
+pub fn print_layout(env: &Environment) -> printer::Layout {
+    let width = env.get("*print-width*");
+    let align_maps = env.get("*print-align-maps*");
+
+    printer::Layout {
+        width: if width.is_int() && width.get_int() > 0 {
+            width.get_int() as usize
+        } else {
+            printer::DEFAULT_WIDTH
+        },
+        align_maps: align_maps.is_bool() && align_maps.get_bool(),
+        highlight: false,
+    }
+}
+
+fn pprint_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    if args.len() != 1 {
+        return MalType::error("pprint takes one argument".to_string());
+    }
+    println!("{}", printer::pprint_str(&args[0], true, &print_layout(env)));
 
     MalType::nil()
 }
 
 fn pr_str_builtin(args: BuiltinFuncArgs) -> MalType {
-    MalType::string(format!("{}", prn_helper(args, true, " ")))
+    MalType::string(format!("{}", prn_helper(args, true, " ", true)))
 }
 
 fn str_builtin(args: BuiltinFuncArgs) -> MalType {
-    MalType::string(format!("{}", prn_helper(args, false, "")))
+    MalType::string(format!("{}", prn_helper(args, false, "", false)))
 }
 
 fn list_builtin(args: BuiltinFuncArgs) -> MalType {
@@ -149,6 +334,12 @@ fn equals_builtin_helper(a: &MalType, b: &MalType) -> bool {
         a.get_string() == b.get_string()
     } else if a.is_symbol() && b.is_symbol() {
         a.get_string() == b.get_string()
+    } else if a.is_char() && b.is_char() {
+        a.get_char() == b.get_char()
+    } else if a.is_regex() && b.is_regex() {
+        a.get_regex().as_str() == b.get_regex().as_str()
+    } else if a.is_handle() && b.is_handle() {
+        a.get_handle() == b.get_handle()
     } else if a.is_atom() && b.is_atom() {
         equals_builtin_helper(&a.get_atom(), &b.get_atom())
     } else if a.is_list() && (b.is_list() || b.is_vector()) {
@@ -363,24 +554,190 @@ fn read_string_builtin(args: BuiltinFuncArgs) -> MalType {
     result
 }
 
+fn io_error(name: &str, path: &str, e: &io::Error) -> MalType {
+    MalType::error(format!("{}: {}: {}", name, path, e))
+}
+
+fn path_arg(args: &BuiltinFuncArgs, index: usize, name: &str) -> Result<Rc<String>, MalType> {
+    match args.get(index) {
+        Some(x) if x.is_string() => Ok(x.get_string()),
+        _ => Err(MalType::error(format!(
+            "{}: argument {} must be a path string",
+            name,
+            index + 1
+        ))),
+    }
+}
+
 fn slurp_builtin(args: BuiltinFuncArgs) -> MalType {
-    use std::fs::File;
-    use std::io::Read;
+    let path = match path_arg(&args, 0, "slurp") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    match fs::read_to_string(&*path) {
+        Ok(contents) => MalType::string(contents),
+        Err(e) => io_error("slurp", &path, &e),
+    }
+}
 
-    for arg in args {
-        if arg.is_string() {
-            let mut file_res = File::open(Rc::make_mut(&mut arg.get_string()));
-            if let Ok(mut file) = file_res {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)
-                    .expect("Unable to read the file");
+//(spit path content) replaces the file, (spit path content :append true) adds to it
+fn spit_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let path = match path_arg(&args, 0, "spit") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    let content = match args.get(1) {
+        Some(x) => printer::pr_str(x, false),
+        None => return MalType::error("spit takes a path and content".to_string()),
+    };
+
+    let mut append = false;
+    for opt in args[2..].chunks(2) {
+        if opt.len() == 2 && opt[0].is_keyword() && *opt[0].get_string() == ":append" {
+            append = !(opt[1].is_nil() || (opt[1].is_bool() && !opt[1].get_bool()));
+        } else {
+            return MalType::error("spit: unknown options, expected :append".to_string());
+        }
+    }
 
-                return MalType::string(contents);
-            }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(&*path);
+
+    match file.and_then(|mut f| f.write_all(content.as_bytes())) {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("spit", &path, &e),
+    }
+}
+
+fn file_exists_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::path::Path;
+
+    match path_arg(&args, 0, "file-exists?") {
+        Ok(p) => MalType::bool(Path::new(&*p).exists()),
+        Err(e) => e,
+    }
+}
+
+fn list_dir_builtin(args: BuiltinFuncArgs) -> MalType {
+    let path = match path_arg(&args, 0, "list-dir") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let entries = fs::read_dir(&*path).and_then(|dir| {
+        dir.map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<String>>>()
+    });
+
+    match entries {
+        Ok(mut names) => {
+            names.sort();
+            MalType::list(names.into_iter().map(MalType::string).collect())
         }
+        Err(e) => io_error("list-dir", &path, &e),
+    }
+}
+
+fn mkdir_builtin(args: BuiltinFuncArgs) -> MalType {
+    let path = match path_arg(&args, 0, "mkdir") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    match fs::create_dir_all(&*path) {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("mkdir", &path, &e),
+    }
+}
+
+//Removes a file or an empty directory
+fn delete_file_builtin(args: BuiltinFuncArgs) -> MalType {
+    let path = match path_arg(&args, 0, "delete-file") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let result = match fs::symlink_metadata(&*path) {
+        Ok(ref m) if m.is_dir() => fs::remove_dir(&*path),
+        Ok(_) => fs::remove_file(&*path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("delete-file", &path, &e),
     }
+}
 
-    MalType::string("".to_string())
+fn rename_file_builtin(args: BuiltinFuncArgs) -> MalType {
+    let (from, to) = match (
+        path_arg(&args, 0, "rename-file"),
+        path_arg(&args, 1, "rename-file"),
+    ) {
+        (Ok(f), Ok(t)) => (f, t),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+
+    match fs::rename(&*from, &*to) {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("rename-file", &from, &e),
+    }
+}
+
+//Returns {:size bytes :mtime millis-since-epoch :dir? bool :file? bool}
+fn file_info_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::time::UNIX_EPOCH;
+
+    let path = match path_arg(&args, 0, "file-info") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    match fs::metadata(&*path) {
+        Ok(m) => {
+            let mtime = match m.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+                Ok(Ok(d)) => MalType::int(d.as_secs() as i64 * 1000 + i64::from(d.subsec_millis())),
+                _ => MalType::nil(),
+            };
+            MalType::map(vec![
+                MalType::keyword(":size".to_string()),
+                MalType::int(m.len() as i64),
+                MalType::keyword(":mtime".to_string()),
+                mtime,
+                MalType::keyword(":dir?".to_string()),
+                MalType::bool(m.is_dir()),
+                MalType::keyword(":file?".to_string()),
+                MalType::bool(m.is_file()),
+            ])
+        }
+        Err(e) => io_error("file-info", &path, &e),
+    }
+}
+
+fn read_lines_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::io::BufRead;
+    use std::io::BufReader;
+
+    let path = match path_arg(&args, 0, "read-lines") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let lines = fs::File::open(&*path)
+        .and_then(|f| BufReader::new(f).lines().collect::<io::Result<Vec<String>>>());
+
+    match lines {
+        Ok(lines) => MalType::list(lines.into_iter().map(MalType::string).collect()),
+        Err(e) => io_error("read-lines", &path, &e),
+    }
 }
 
 fn atom_builtin(args: BuiltinFuncArgs) -> MalType {
@@ -456,7 +813,7 @@ fn swap_builtin(args: BuiltinFuncArgs) -> MalType {
             atom.set_atom(result.clone());
             return result;
         } else if func.is_func_tco() {
-            let (_args, _body, _env, func, _is_macro) = func.get_func_tco();
+            let (_arities, _env, func, _is_macro) = func.get_func_tco();
             let result = func(func_args);
             atom.set_atom(result.clone());
             return result;
@@ -570,3 +927,1055 @@ fn rest_builtin(args: BuiltinFuncArgs) -> MalType {
         }
     }
 }
+
+fn call_func(func: &MalType, args: BuiltinFuncArgs) -> MalType {
+    if func.is_func() {
+        let (f, _is_macro) = func.get_func();
+        f(args)
+    } else if func.is_func_tco() {
+        let (_arities, _env, f, _is_macro) = func.get_func_tco();
+        f(args)
+    } else {
+        MalType::error(format!("{} is not a function", printer::pr_str(func, true)))
+    }
+}
+
+//Accepts either a regex value or a string pattern, compiling strings through the cache
+fn to_regex(arg: &MalType, name: &str) -> Result<Rc<Regex>, MalType> {
+    if arg.is_regex() {
+        Ok(arg.get_regex())
+    } else if arg.is_string() {
+        compile_regex(&arg.get_string()).map_err(MalType::error)
+    } else {
+        Err(MalType::error(format!(
+            "{}: first argument must be a regex or string",
+            name
+        )))
+    }
+}
+
+//A match without groups is the matched string, otherwise a vector of the whole match and groups
+fn captures_to_mal(caps: &Captures) -> MalType {
+    if caps.len() == 1 {
+        MalType::string(caps[0].to_string())
+    } else {
+        MalType::vector(
+            caps.iter()
+                .map(|m| match m {
+                    Some(m) => MalType::string(m.as_str().to_string()),
+                    None => MalType::nil(),
+                }).collect(),
+        )
+    }
+}
+
+fn regex_args(args: &BuiltinFuncArgs, name: &str) -> Result<(Rc<Regex>, Rc<String>), MalType> {
+    if args.len() != 2 {
+        return Err(MalType::error(format!("{} takes exactly 2 arguments", name)));
+    }
+    let re = to_regex(&args[0], name)?;
+    if !args[1].is_string() {
+        return Err(MalType::error(format!(
+            "{}: second argument must be a string",
+            name
+        )));
+    }
+
+    Ok((re, args[1].get_string()))
+}
+
+fn re_pattern_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_regex() => x.clone(),
+        Some(x) if x.is_string() => match compile_regex(&x.get_string()) {
+            Ok(re) => MalType::regex(re),
+            Err(e) => MalType::error(e),
+        },
+        _ => MalType::error("re-pattern takes a string argument".to_string()),
+    }
+}
+
+fn regex_test_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) => MalType::bool(x.is_regex()),
+        _ => MalType::bool(false),
+    }
+}
+
+fn re_find_builtin(args: BuiltinFuncArgs) -> MalType {
+    match regex_args(&args, "re-find") {
+        Ok((re, s)) => match re.captures(&s) {
+            Some(caps) => captures_to_mal(&caps),
+            None => MalType::nil(),
+        },
+        Err(e) => e,
+    }
+}
+
+fn re_matches_builtin(args: BuiltinFuncArgs) -> MalType {
+    match regex_args(&args, "re-matches") {
+        Ok((re, s)) => match compile_regex(&format!("^(?:{})$", re.as_str())) {
+            Ok(anchored) => match anchored.captures(&s) {
+                Some(caps) => captures_to_mal(&caps),
+                None => MalType::nil(),
+            },
+            Err(e) => MalType::error(e),
+        },
+        Err(e) => e,
+    }
+}
+
+fn re_seq_builtin(args: BuiltinFuncArgs) -> MalType {
+    match regex_args(&args, "re-seq") {
+        Ok((re, s)) => MalType::list(re.captures_iter(&s).map(|c| captures_to_mal(&c)).collect()),
+        Err(e) => e,
+    }
+}
+
+fn re_groups_builtin(args: BuiltinFuncArgs) -> MalType {
+    match regex_args(&args, "re-groups") {
+        Ok((re, s)) => match re.captures(&s) {
+            Some(caps) => {
+                let names: Vec<&str> = re.capture_names().filter_map(|n| n).collect();
+                if names.is_empty() {
+                    captures_to_mal(&caps)
+                } else {
+                    let mut m: Vec<MalType> = Vec::new();
+                    for name in names {
+                        m.push(MalType::keyword(format!(":{}", name)));
+                        m.push(match caps.name(name) {
+                            Some(v) => MalType::string(v.as_str().to_string()),
+                            None => MalType::nil(),
+                        });
+                    }
+                    MalType::map(m)
+                }
+            }
+            None => MalType::nil(),
+        },
+        Err(e) => e,
+    }
+}
+
+fn replace_builtin(args: BuiltinFuncArgs) -> MalType {
+    if args.len() != 3 {
+        return MalType::error("replace takes exactly 3 arguments".to_string());
+    }
+    if !args[0].is_string() {
+        return MalType::error("replace: first argument must be a string".to_string());
+    }
+    let s = args[0].get_string();
+    let replacement = &args[2];
+
+    if args[1].is_string() && replacement.is_string() {
+        MalType::string(s.replace(&*args[1].get_string(), &replacement.get_string()))
+    } else if args[1].is_regex() {
+        let re = args[1].get_regex();
+        if replacement.is_string() {
+            MalType::string(re.replace_all(&s, replacement.get_string().as_str()).to_string())
+        } else if replacement.is_func() || replacement.is_func_tco() {
+            let mut error: Option<MalType> = None;
+            let result = re.replace_all(&s, |caps: &Captures| {
+                let r = call_func(replacement, vec![captures_to_mal(caps)]);
+                if r.is_error() && error.is_none() {
+                    error = Some(r.clone());
+                }
+                printer::pr_str(&r, false)
+            });
+            match error {
+                Some(e) => e,
+                None => MalType::string(result.to_string()),
+            }
+        } else {
+            MalType::error("replace: replacement must be a string or function".to_string())
+        }
+    } else {
+        MalType::error("replace: match must be a string or regex".to_string())
+    }
+}
+
+fn split_builtin(args: BuiltinFuncArgs) -> MalType {
+    if args.len() != 2 || !args[0].is_string() {
+        return MalType::error("split takes a string and a separator".to_string());
+    }
+    let s = args[0].get_string();
+
+    let parts: Vec<MalType> = if args[1].is_string() {
+        s.split(args[1].get_string().as_str())
+            .map(|p| MalType::string(p.to_string()))
+            .collect()
+    } else if args[1].is_regex() {
+        args[1]
+            .get_regex()
+            .split(&s)
+            .map(|p| MalType::string(p.to_string()))
+            .collect()
+    } else {
+        return MalType::error("split: separator must be a string or regex".to_string());
+    };
+
+    MalType::list(parts)
+}
+
+fn char_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::char;
+
+    match args.get(0) {
+        Some(x) if x.is_char() => x.clone(),
+        Some(x) if x.is_int() => {
+            let code = x.get_int();
+            if code >= 0 && code <= i64::from(u32::max_value()) {
+                if let Some(c) = char::from_u32(code as u32) {
+                    return MalType::char(c);
+                }
+            }
+            MalType::error(format!("char: {} is not a valid code point", code))
+        }
+        Some(x) if x.is_string() && x.get_string().chars().count() == 1 => {
+            MalType::char(x.get_string().chars().next().unwrap())
+        }
+        _ => MalType::error("char takes an int or a single character string".to_string()),
+    }
+}
+
+fn char_test_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) => MalType::bool(x.is_char()),
+        _ => MalType::bool(false),
+    }
+}
+
+fn int_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_char() => MalType::int(i64::from(x.get_char() as u32)),
+        Some(x) if x.is_int() || x.is_float() => MalType::int(x.get_int()),
+        _ => MalType::error("int takes a char or a number".to_string()),
+    }
+}
+
+fn seq_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_list() || x.is_vector() => {
+            let l = x.get_list();
+            if l.is_empty() {
+                MalType::nil()
+            } else {
+                MalType::list(l.to_vec())
+            }
+        }
+        Some(x) if x.is_string() => {
+            let s = x.get_string();
+            if s.is_empty() {
+                MalType::nil()
+            } else {
+                MalType::list(s.chars().map(MalType::char).collect())
+            }
+        }
+        Some(x) if x.is_nil() => MalType::nil(),
+        _ => MalType::error("seq: argument must be a list, vector, string or nil".to_string()),
+    }
+}
+
+fn vec_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_list() || x.is_vector() => MalType::vector(x.get_list().to_vec()),
+        Some(x) if x.is_nil() => MalType::vector(Vec::new()),
+        _ => MalType::error("vec: argument must be a list, vector or nil".to_string()),
+    }
+}
+
+//(hash-map k v ...), a later value replacing an earlier one for the same key
+fn hash_map_builtin(args: BuiltinFuncArgs) -> MalType {
+    if args.len() % 2 == 1 {
+        return MalType::error("hash-map takes an even number of arguments".to_string());
+    }
+
+    let mut entries: Vec<MalType> = Vec::new();
+    for pair in args.chunks(2) {
+        let existing = entries
+            .chunks(2)
+            .position(|e| equals_builtin_helper(&e[0], &pair[0]));
+        match existing {
+            Some(i) => entries[i * 2 + 1] = pair[1].clone(),
+            None => entries.extend_from_slice(pair),
+        }
+    }
+    MalType::map(entries)
+}
+
+thread_local! {
+    static GENSYM_COUNTER: Cell<u64> = Cell::new(0);
+}
+
+//A fresh symbol named prefix followed by a number no earlier gensym has used
+pub fn gensym(prefix: &str) -> MalType {
+    let id = GENSYM_COUNTER.with(|c| {
+        c.set(c.get() + 1);
+        c.get()
+    });
+    MalType::symbol(format!("{}{}", prefix, id))
+}
+
+//(gensym) or (gensym prefix), for macros to bind names that can't capture the caller's
+fn gensym_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        None => gensym("G__"),
+        Some(x) if x.is_string() => gensym(&x.get_string()),
+        _ => MalType::error("gensym takes an optional prefix string".to_string()),
+    }
+}
+
+fn handle_arg(args: &BuiltinFuncArgs, name: &str) -> Result<Rc<MalHandle>, MalType> {
+    match args.get(0) {
+        Some(x) if x.is_handle() => Ok(x.get_handle()),
+        _ => Err(MalType::error(format!(
+            "{}: first argument must be a handle",
+            name
+        ))),
+    }
+}
+
+//(open path) reads, (open path :write) truncates and (open path :append) appends
+fn open_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::fs::OpenOptions;
+
+    let path = match path_arg(&args, 0, "open") {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    let mode = match args.get(1) {
+        Some(m) if m.is_keyword() => m.get_string().to_string(),
+        Some(_) => return MalType::error("open: mode must be :read, :write or :append".to_string()),
+        None => ":read".to_string(),
+    };
+
+    let handle = match &*mode {
+        ":read" => fs::File::open(&*path)
+            .map(|f| MalHandle::input(path.to_string(), Box::new(io::BufReader::new(f)))),
+        ":write" | ":append" => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(mode == ":append")
+            .truncate(mode == ":write")
+            .open(&*path)
+            .map(|f| MalHandle::output(path.to_string(), Box::new(io::BufWriter::new(f)))),
+        _ => return MalType::error(format!("open: unknown mode {}", mode)),
+    };
+
+    match handle {
+        Ok(h) => MalType::handle(h),
+        Err(e) => io_error("open", &path, &e),
+    }
+}
+
+fn handle_test_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) => MalType::bool(x.is_handle()),
+        _ => MalType::bool(false),
+    }
+}
+
+//Returns the next line without its line ending, or nil at end of input
+fn read_line_builtin(args: BuiltinFuncArgs) -> MalType {
+    let h = match handle_arg(&args, "read-line") {
+        Ok(h) => h,
+        Err(e) => return e,
+    };
+
+    let result = match *h.stream.borrow_mut() {
+        HandleStream::Input(ref mut r) => {
+            let mut line = String::new();
+            r.read_line(&mut line).map(|n| (n, line))
+        }
+        _ => return MalType::error(format!("read-line: {} is not open for reading", h.name)),
+    };
+
+    match result {
+        Ok((0, _)) => MalType::nil(),
+        Ok((_, mut line)) => {
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            MalType::string(line)
+        }
+        Err(e) => io_error("read-line", &h.name, &e),
+    }
+}
+
+fn read_utf8_char(r: &mut dyn io::BufRead) -> io::Result<Option<char>> {
+    let mut buf = [0u8; 4];
+    if r.read(&mut buf[..1])? == 0 {
+        return Ok(None);
+    }
+
+    let width = match buf[0] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 0,
+    };
+    if width > 1 {
+        r.read_exact(&mut buf[1..width])?;
+    }
+
+    match ::std::str::from_utf8(&buf[..width]) {
+        Ok(s) if width > 0 => Ok(s.chars().next()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )),
+    }
+}
+
+//Returns the next character, or nil at end of input
+fn read_char_builtin(args: BuiltinFuncArgs) -> MalType {
+    let h = match handle_arg(&args, "read-char") {
+        Ok(h) => h,
+        Err(e) => return e,
+    };
+
+    let result = match *h.stream.borrow_mut() {
+        HandleStream::Input(ref mut r) => read_utf8_char(&mut **r),
+        _ => return MalType::error(format!("read-char: {} is not open for reading", h.name)),
+    };
+
+    match result {
+        Ok(Some(c)) => MalType::char(c),
+        Ok(None) => MalType::nil(),
+        Err(e) => io_error("read-char", &h.name, &e),
+    }
+}
+
+//Writes the remaining arguments as str would format them
+fn write_builtin(args: BuiltinFuncArgs) -> MalType {
+    let h = match handle_arg(&args, "write") {
+        Ok(h) => h,
+        Err(e) => return e,
+    };
+    let content = prn_helper(args[1..].to_vec(), false, "", false);
+
+    let result = match *h.stream.borrow_mut() {
+        HandleStream::Output(ref mut w) => w.write_all(content.as_bytes()),
+        _ => return MalType::error(format!("write: {} is not open for writing", h.name)),
+    };
+
+    match result {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("write", &h.name, &e),
+    }
+}
+
+fn flush_builtin(args: BuiltinFuncArgs) -> MalType {
+    let h = match handle_arg(&args, "flush") {
+        Ok(h) => h,
+        Err(e) => return e,
+    };
+
+    let result = match *h.stream.borrow_mut() {
+        HandleStream::Output(ref mut w) => w.flush(),
+        HandleStream::Input(_) => Ok(()),
+        HandleStream::Closed => return MalType::error(format!("flush: {} is closed", h.name)),
+    };
+
+    match result {
+        Ok(_) => MalType::nil(),
+        Err(e) => io_error("flush", &h.name, &e),
+    }
+}
+
+fn close_builtin(args: BuiltinFuncArgs) -> MalType {
+    match handle_arg(&args, "close") {
+        Ok(h) => close_handle(&h),
+        Err(e) => e,
+    }
+}
+
+//Flushes pending output and releases the underlying stream, closing twice is harmless
+pub fn close_handle(h: &MalHandle) -> MalType {
+    use std::mem;
+
+    let stream = mem::replace(&mut *h.stream.borrow_mut(), HandleStream::Closed);
+    if let HandleStream::Output(mut w) = stream {
+        if let Err(e) = w.flush() {
+            return io_error("close", &h.name, &e);
+        }
+    }
+
+    MalType::nil()
+}
+
+struct ShOptions {
+    input: Option<Rc<String>>,
+    timeout: Option<Duration>,
+}
+
+//Builds the Command from (cmd arg... :in s :dir d :env {k v} :timeout ms)
+fn sh_command(args: &BuiltinFuncArgs, name: &str) -> Result<(Command, ShOptions), MalType> {
+    let mut words: Vec<Rc<String>> = Vec::new();
+    let mut i = 0;
+    while i < args.len() && args[i].is_string() {
+        words.push(args[i].get_string());
+        i += 1;
+    }
+    if words.is_empty() {
+        return Err(MalType::error(format!("{}: expected a command string", name)));
+    }
+
+    let mut cmd = Command::new(&*words[0]);
+    cmd.args(words[1..].iter().map(|w| w.as_str()));
+    let mut opts = ShOptions {
+        input: None,
+        timeout: None,
+    };
+
+    for opt in args[i..].chunks(2) {
+        if opt.len() != 2 || !opt[0].is_keyword() {
+            return Err(MalType::error(format!(
+                "{}: options must be keyword/value pairs",
+                name
+            )));
+        }
+        let (key, val) = (opt[0].get_string(), &opt[1]);
+        match key.as_str() {
+            ":in" if val.is_string() => opts.input = Some(val.get_string()),
+            ":dir" if val.is_string() => {
+                cmd.current_dir(&*val.get_string());
+            }
+            ":env" if val.is_map() => {
+                for pair in val.get_list().chunks(2) {
+                    if !pair[0].is_string() {
+                        return Err(MalType::error(format!(
+                            "{}: :env keys must be strings, not {}",
+                            name,
+                            printer::pr_str(&pair[0], true)
+                        )));
+                    }
+                    cmd.env(&*pair[0].get_string(), printer::pr_str(&pair[1], false));
+                }
+            }
+            ":timeout" if val.is_int() && val.get_int() >= 0 => {
+                opts.timeout = Some(Duration::from_millis(val.get_int() as u64))
+            }
+            _ => {
+                return Err(MalType::error(format!(
+                    "{}: bad value for option {}",
+                    name, key
+                )))
+            }
+        }
+    }
+
+    Ok((cmd, opts))
+}
+
+fn exit_code(status: ::std::process::ExitStatus) -> MalType {
+    match status.code() {
+        Some(code) => MalType::int(i64::from(code)),
+        None => MalType::nil(),
+    }
+}
+
+//Waits for the child, killing it once the optional timeout has passed
+fn wait_child(child: &mut Child, timeout: Option<Duration>) -> io::Result<Option<MalType>> {
+    let deadline = match timeout {
+        Some(t) => Instant::now() + t,
+        None => return child.wait().map(|s| Some(exit_code(s))),
+    };
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(exit_code(status)));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+//Runs a command to completion, returning {:exit code :out stdout :err stderr}
+fn sh_builtin(args: BuiltinFuncArgs) -> MalType {
+    use std::io::{Read, Write};
+
+    let (mut cmd, opts) = match sh_command(&args, "sh") {
+        Ok(c) => c,
+        Err(e) => return e,
+    };
+    let program = args[0].get_string();
+
+    let spawned = cmd
+        .stdin(if opts.input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        }).stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(c) => c,
+        Err(e) => return io_error("sh", &program, &e),
+    };
+
+    //feed and drain the pipes on their own threads so a chatty child can't deadlock us
+    let writer = match (child.stdin.take(), opts.input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_string();
+            Some(thread::spawn(move || stdin.write_all(input.as_bytes())))
+        }
+        _ => None,
+    };
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut p) = pipe {
+                let _ = p.read_to_end(&mut buf);
+            }
+            String::from_utf8_lossy(&buf).into_owned()
+        })
+    };
+    let out = drain(child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+    let err = drain(child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+
+    //after a timeout a grandchild may still hold the pipes open, so leave the threads be
+    let code = match wait_child(&mut child, opts.timeout) {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            return MalType::error(format!(
+                "sh: {} timed out after {} ms",
+                program,
+                opts.timeout.map_or(0, |t| t.as_secs() * 1000 + u64::from(t.subsec_millis()))
+            ))
+        }
+        Err(e) => return io_error("sh", &program, &e),
+    };
+    if let Some(w) = writer {
+        let _ = w.join();
+    }
+    let out = out.join().unwrap_or_default();
+    let err = err.join().unwrap_or_default();
+
+    MalType::map(vec![
+        MalType::keyword(":exit".to_string()),
+        code,
+        MalType::keyword(":out".to_string()),
+        MalType::string(out),
+        MalType::keyword(":err".to_string()),
+        MalType::string(err),
+    ])
+}
+
+//Starts a command and returns {:in :out :err} handles plus a :wait function giving the exit code.
+//Close :in before waiting on a command that reads until end of input.
+fn sh_stream_builtin(args: BuiltinFuncArgs) -> MalType {
+    let (mut cmd, opts) = match sh_command(&args, "sh-stream") {
+        Ok(c) => c,
+        Err(e) => return e,
+    };
+    if opts.input.is_some() {
+        return MalType::error("sh-stream: write to the :in handle instead of passing :in".to_string());
+    }
+    let program = args[0].get_string();
+
+    let spawned = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(c) => c,
+        Err(e) => return io_error("sh-stream", &program, &e),
+    };
+
+    let mut result: Vec<MalType> = Vec::new();
+    if let Some(stdin) = child.stdin.take() {
+        result.push(MalType::keyword(":in".to_string()));
+        result.push(MalType::handle(MalHandle::output(
+            format!("{}:in", program),
+            Box::new(stdin),
+        )));
+    }
+    if let Some(stdout) = child.stdout.take() {
+        result.push(MalType::keyword(":out".to_string()));
+        result.push(MalType::handle(MalHandle::input(
+            format!("{}:out", program),
+            Box::new(io::BufReader::new(stdout)),
+        )));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        result.push(MalType::keyword(":err".to_string()));
+        result.push(MalType::handle(MalHandle::input(
+            format!("{}:err", program),
+            Box::new(io::BufReader::new(stderr)),
+        )));
+    }
+
+    let child = RefCell::new(child);
+    let timeout = opts.timeout;
+    let wait = move |_args: BuiltinFuncArgs| match wait_child(&mut child.borrow_mut(), timeout) {
+        Ok(Some(code)) => code,
+        Ok(None) => MalType::error(format!("sh-stream: {} timed out", program)),
+        Err(e) => io_error("sh-stream", &program, &e),
+    };
+    result.push(MalType::keyword(":wait".to_string()));
+    result.push(MalType::func(Rc::new(Box::new(wait)), false));
+
+    MalType::map(result)
+}
+
+thread_local! {
+    static EXIT_STATUS: Cell<Option<i32>> = Cell::new(None);
+}
+
+//Set once (exit) has been called, eval stops doing work so main can return the status
+pub fn exit_status() -> Option<i32> {
+    EXIT_STATUS.with(|s| s.get())
+}
+
+//Evaluates each form of a source text in turn, stopping at the first error.
+//A leading #! line is skipped so scripts can be made executable.
+pub fn eval_source(source: &str, env: &mut Environment) -> MalType {
+    let source = if source.starts_with("#!") {
+        source.find('\n').map_or("", |i| &source[i..])
+    } else {
+        source
+    };
+
+    let forms = match read_all(source) {
+        Ok(forms) => forms,
+        Err(e) => return e,
+    };
+    let mut result = MalType::nil();
+    for form in forms {
+        result = eval::eval(&form, env);
+        if result.is_error() || exit_status().is_some() {
+            break;
+        }
+    }
+    result
+}
+
+//Evaluates a file in the current namespace with *file* bound to its path
+pub fn load_file(env: &Environment, path: &str) -> MalType {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return io_error("load-file", path, &e),
+    };
+
+    //a file switching namespace with (ns ...) leaves the caller's as it was
+    let mut root = env.get_root();
+    let previous = root.get("*file*");
+    let previous_ns = root.current_ns();
+    root.set("*file*", MalType::string(path.to_string()));
+    let result = eval_source(&contents, &mut root);
+    root.set("*file*", previous);
+    root.in_ns(&previous_ns);
+
+    result
+}
+
+fn load_file_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    match path_arg(&args, 0, "load-file") {
+        Ok(p) => load_file(env, &p),
+        Err(e) => e,
+    }
+}
+
+//(in-ns 'name) switches the namespace later top level forms are evaluated in
+fn in_ns_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_symbol() && !x.get_string().contains('/') => {
+            env.in_ns(&x.get_string());
+            x.clone()
+        }
+        _ => MalType::error("in-ns takes a namespace symbol".to_string()),
+    }
+}
+
+//(ns name (:require spec...)) switches to name, then requires what it lists
+fn ns_macro(args: BuiltinFuncArgs) -> MalType {
+    let symbol = |name: &str| MalType::symbol(name.to_string());
+    let quote = |form: &MalType| MalType::list(vec![symbol("quote"), form.clone()]);
+    let name = match args.get(0) {
+        Some(x) if x.is_symbol() => x,
+        _ => return MalType::error("ns takes a namespace symbol".to_string()),
+    };
+
+    let mut forms = vec![
+        symbol("do"),
+        MalType::list(vec![symbol("in-ns"), quote(name)]),
+    ];
+    for clause in args[1..].iter() {
+        let l = if clause.is_list() {
+            clause.get_list()
+        } else {
+            Rc::new(Vec::new())
+        };
+        match l.first() {
+            Some(k) if k.is_keyword() && *k.get_string() == ":require" => {
+                let mut require = vec![symbol("require")];
+                require.extend(l[1..].iter().map(|spec| quote(spec)));
+                forms.push(MalType::list(require));
+            }
+            _ => {
+                return MalType::error(format!(
+                    "ns: unsupported clause {}",
+                    printer::pr_str(clause, true)
+                ))
+            }
+        }
+    }
+    MalType::list(forms)
+}
+
+//(require 'foo.bar ...) or (require '[foo.bar :as fb :refer [x y]]) loads each
+//namespace once, then aliases it or refers to its definitions from the current one
+fn require_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    for spec in args.iter() {
+        if let Err(e) = require(env, spec) {
+            return e;
+        }
+    }
+    MalType::nil()
+}
+
+fn require(env: &Environment, spec: &MalType) -> Result<(), MalType> {
+    let (name, options) = if spec.is_symbol() {
+        (spec.get_string(), Vec::new())
+    } else if (spec.is_vector() || spec.is_list())
+        && spec.get_list().first().map_or(false, |n| n.is_symbol())
+    {
+        let l = spec.get_list();
+        (l[0].get_string(), l[1..].to_vec())
+    } else {
+        return Err(MalType::error(format!(
+            "require takes namespace symbols or [name :as alias :refer [names]], not {}",
+            printer::pr_str(spec, true)
+        )));
+    };
+
+    load_module(env, &name)?;
+
+    for option in options.chunks(2) {
+        let key = if option[0].is_keyword() {
+            option[0].get_string().to_string()
+        } else {
+            String::new()
+        };
+        match (key.as_str(), option.get(1)) {
+            (":as", Some(a)) if a.is_symbol() => env.alias(&a.get_string(), &name)?,
+            (":refer", Some(r)) if r.is_keyword() && *r.get_string() == ":all" => {
+                env.refer(&name, None)?
+            }
+            (":refer", Some(r))
+                if (r.is_vector() || r.is_list()) && r.get_list().iter().all(|n| n.is_symbol()) =>
+            {
+                let names = r
+                    .get_list()
+                    .iter()
+                    .map(|n| n.get_string().to_string())
+                    .collect();
+                env.refer(&name, Some(names))?
+            }
+            _ => {
+                return Err(MalType::error(format!(
+                    "require: bad option in {}",
+                    printer::pr_str(spec, true)
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+//Loads the file for a namespace unless it already has been, refusing to start
+//on one that is still being loaded further up the chain of requires
+fn load_module(env: &Environment, name: &str) -> Result<(), MalType> {
+    {
+        let namespaces = env.namespaces.borrow();
+        if namespaces.loaded.contains(name) {
+            return Ok(());
+        }
+        if let Some(i) = namespaces.loading.iter().position(|n| n == name) {
+            let mut chain = namespaces.loading[i..].to_vec();
+            chain.push(name.to_string());
+            return Err(MalType::error(format!(
+                "circular require: {}",
+                chain.join(" -> ")
+            )));
+        }
+    }
+
+    let path = find_module(env, name)?;
+    let before: HashSet<String> = env.namespaces.borrow().table.keys().cloned().collect();
+    env.namespaces.borrow_mut().loading.push(name.to_string());
+    let mut result = load_file(env, &path);
+    env.namespaces.borrow_mut().loading.pop();
+
+    let mut namespaces = env.namespaces.borrow_mut();
+    if !result.is_error() && !namespaces.table.contains_key(name) {
+        result = MalType::error(format!(
+            "require: {} doesn't define namespace {}",
+            path, name
+        ));
+    }
+    if result.is_error() {
+        //drop the namespaces the failed load made, so requiring it again starts clean
+        namespaces.table.retain(|ns, _| before.contains(ns));
+        namespaces.loaded.retain(|ns| before.contains(ns));
+        return Err(result);
+    }
+    namespaces.loaded.insert(name.to_string());
+    Ok(())
+}
+
+//foo.bar is foo/bar.mal in the first directory of *load-path* that has it
+fn find_module(env: &Environment, name: &str) -> Result<String, MalType> {
+    let file = format!("{}.mal", name.replace('.', "/"));
+    let load_path = env.get_root().get("*load-path*");
+    if !(load_path.is_vector() || load_path.is_list()) {
+        return Err(MalType::error(
+            "*load-path* must be a vector of directories".to_string(),
+        ));
+    }
+
+    for dir in load_path.get_list().iter().filter(|d| d.is_string()) {
+        let path = Path::new(&*dir.get_string()).join(&file);
+        if path.is_file() {
+            return Ok(path.to_string_lossy().into_owned());
+        }
+    }
+    Err(MalType::error(format!(
+        "require: can't find {} in *load-path*",
+        file
+    )))
+}
+
+//(refer 'ns) or (refer 'ns :only [a b]) makes another namespace's definitions usable unqualified
+fn refer_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    let only = match (args.get(1), args.get(2)) {
+        (None, _) => None,
+        (Some(k), Some(names)) if k.is_keyword() && *k.get_string() == ":only" => {
+            if !(names.is_list() || names.is_vector())
+                || !names.get_list().iter().all(|n| n.is_symbol())
+            {
+                return MalType::error("refer :only takes a vector of symbols".to_string());
+            }
+            Some(
+                names
+                    .get_list()
+                    .iter()
+                    .map(|n| n.get_string().to_string())
+                    .collect(),
+            )
+        }
+        _ => {
+            return MalType::error(
+                "refer takes a namespace and optionally :only [names]".to_string(),
+            )
+        }
+    };
+
+    match args.get(0) {
+        Some(x) if x.is_symbol() => match env.refer(&x.get_string(), only) {
+            Ok(()) => MalType::nil(),
+            Err(e) => e,
+        },
+        _ => MalType::error("refer takes a namespace and optionally :only [names]".to_string()),
+    }
+}
+
+//(alias 'short 'full.name) lets the current namespace write short/x for full.name/x
+fn alias_builtin(env: &Environment, args: BuiltinFuncArgs) -> MalType {
+    match (args.get(0), args.get(1)) {
+        (Some(short), Some(full)) if short.is_symbol() && full.is_symbol() => {
+            match env.alias(&short.get_string(), &full.get_string()) {
+                Ok(()) => MalType::nil(),
+                Err(e) => e,
+            }
+        }
+        _ => MalType::error("alias takes an alias and a namespace symbol".to_string()),
+    }
+}
+
+//(getenv name) or (getenv name default)
+fn getenv_builtin(args: BuiltinFuncArgs) -> MalType {
+    match args.get(0) {
+        Some(x) if x.is_string() => match env::var(&*x.get_string()) {
+            Ok(v) => MalType::string(v),
+            Err(_) => args.get(1).cloned().unwrap_or_else(MalType::nil),
+        },
+        _ => MalType::error("getenv takes a variable name".to_string()),
+    }
+}
+
+//(setenv name value) sets a variable for this process and its children, a nil value removes it
+fn setenv_builtin(args: BuiltinFuncArgs) -> MalType {
+    if args.len() != 2 || !args[0].is_string() {
+        return MalType::error("setenv takes a variable name and a value".to_string());
+    }
+    let name = args[0].get_string();
+
+    if args[1].is_nil() {
+        env::remove_var(&*name);
+    } else {
+        env::set_var(&*name, printer::pr_str(&args[1], false));
+    }
+
+    MalType::nil()
+}
+
+fn env_map_builtin(_args: BuiltinFuncArgs) -> MalType {
+    let mut vars: Vec<(String, String)> = env::vars().collect();
+    vars.sort();
+
+    let mut m: Vec<MalType> = Vec::new();
+    for (k, v) in vars {
+        m.push(MalType::string(k));
+        m.push(MalType::string(v));
+    }
+    MalType::map(m)
+}
+
+//(exit) or (exit status)
+fn exit_builtin(args: BuiltinFuncArgs) -> MalType {
+    let status = match args.get(0) {
+        Some(x) if x.is_int() => x.get_int() as i32,
+        None => 0,
+        _ => return MalType::error("exit status must be an int".to_string()),
+    };
+
+    EXIT_STATUS.with(|s| s.set(Some(status)));
+    MalType::nil()
+}
+
+fn cwd_builtin(_args: BuiltinFuncArgs) -> MalType {
+    match env::current_dir() {
+        Ok(p) => MalType::string(p.to_string_lossy().into_owned()),
+        Err(e) => MalType::error(format!("cwd: {}", e)),
+    }
+}
+
+fn hostname_builtin(_args: BuiltinFuncArgs) -> MalType {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .or_else(|_| {
+            Command::new("hostname")
+                .output()
+                .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+        });
+
+    match name {
+        Ok(n) => MalType::string(n.trim().to_string()),
+        Err(e) => MalType::error(format!("hostname: {}", e)),
+    }
+}
+
+fn pid_builtin(_args: BuiltinFuncArgs) -> MalType {
+    MalType::int(i64::from(::std::process::id()))
+}
@@ -1,18 +1,60 @@
+use core;
 use printer::pr_str;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use types::Arity;
 use types::BuiltinFuncArgs;
 use types::MalType;
 
 pub type EnvScope = HashMap<String, MalType>;
 
+//Nested calls of eval allowed before erroring, used when *max-depth* isn't an int;
+//main runs the interpreter on a thread whose stack has room for this many
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+//Stack for the interpreter thread, and the most nested evals it has room for:
+//each takes up to about 10KB of it in a debug build, so *max-depth* is capped there
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+pub const MAX_DEPTH_CEILING: usize = STACK_SIZE / (10 * 1024);
+
+thread_local! {
+    //active calls of eval, and the limit read from *max-depth* for the current top level form
+    static DEPTH: Cell<usize> = Cell::new(0);
+    static MAX_DEPTH: Cell<usize> = Cell::new(DEFAULT_MAX_DEPTH);
+}
+
+//The namespace whose definitions are the root scope, alongside the builtins
+pub const DEFAULT_NS: &str = "user";
+
+//A namespace's own definitions and the short names it uses for other namespaces
+#[derive(Debug)]
+pub struct Namespace {
+    pub map: Rc<RefCell<EnvScope>>,
+    pub aliases: HashMap<String, String>,
+}
+
+//Every namespace by name, the one top level forms are evaluated in,
+//and those require has loaded or is in the middle of loading
+#[derive(Debug)]
+pub struct Namespaces {
+    pub current: String,
+    pub table: HashMap<String, Namespace>,
+    pub loaded: HashSet<String>,
+    pub loading: Vec<String>,
+}
+
 //Defining Environment type for mal
 
 #[derive(Debug, Clone)]
 pub struct Environment {
     pub map: Rc<RefCell<EnvScope>>,
     pub outer: Option<Rc<RefCell<Environment>>>,
+    //ns/name of the vars binding may rebind, shared by every scope
+    pub dynamic: Rc<RefCell<HashSet<String>>>,
+    //the namespace this scope's code was written in, and all of them, shared by every scope
+    pub ns: Rc<String>,
+    pub namespaces: Rc<RefCell<Namespaces>>,
 }
 
 impl PartialEq for Environment {
@@ -23,9 +65,27 @@ impl PartialEq for Environment {
 
 impl Environment {
     pub fn new() -> Environment {
+        let map = Rc::new(RefCell::new(HashMap::new()));
+        let mut table = HashMap::new();
+        table.insert(
+            DEFAULT_NS.to_string(),
+            Namespace {
+                map: map.clone(),
+                aliases: HashMap::new(),
+            },
+        );
+
         Environment {
-            map: Rc::new(RefCell::new(HashMap::new())),
+            map,
             outer: None,
+            dynamic: Rc::new(RefCell::new(HashSet::new())),
+            ns: Rc::new(DEFAULT_NS.to_string()),
+            namespaces: Rc::new(RefCell::new(Namespaces {
+                current: DEFAULT_NS.to_string(),
+                table,
+                loaded: HashSet::new(),
+                loading: Vec::new(),
+            })),
         }
     }
 
@@ -36,17 +96,46 @@ impl Environment {
     }
 
     pub fn find(&self, key: &str) -> Option<MalType> {
+        self.find_scope(key).or_else(|| self.find_qualified(key))
+    }
+
+    fn find_scope(&self, key: &str) -> Option<MalType> {
         if let Some(x) = self.map.borrow().get(key) {
             Some(x.clone())
         } else {
             if let Some(out) = self.outer.clone() {
-                out.borrow().find(key)
+                out.borrow().find_scope(key)
             } else {
                 None
             }
         }
     }
 
+    //Looks up ns/name in that namespace's own definitions, ns being a full name
+    //or an alias made in the namespace this scope belongs to
+    fn find_qualified(&self, key: &str) -> Option<MalType> {
+        let slash = key.find('/')?;
+        if slash == 0 || slash == key.len() - 1 {
+            return None;
+        }
+        let (prefix, name) = (&key[..slash], &key[slash + 1..]);
+
+        let namespaces = self.namespaces.borrow();
+        namespaces
+            .table
+            .get(self.full_ns(&namespaces, prefix))
+            .and_then(|ns| ns.map.borrow().get(name).cloned())
+    }
+
+    //The namespace a prefix names, through the aliases of this scope's namespace
+    fn full_ns<'a>(&self, namespaces: &'a Namespaces, prefix: &'a str) -> &'a str {
+        namespaces
+            .table
+            .get(&*self.ns)
+            .and_then(|ns| ns.aliases.get(prefix))
+            .map_or(prefix, |full| full.as_str())
+    }
+
     pub fn get(&self, key: &str) -> MalType {
         match self.find(key.clone()) {
             Some(v) => v,
@@ -58,7 +147,131 @@ impl Environment {
         Environment {
             map: Rc::new(RefCell::new(HashMap::new())),
             outer: Some(Rc::new(RefCell::new(self.clone()))),
+            dynamic: self.dynamic.clone(),
+            ns: self.ns.clone(),
+            namespaces: self.namespaces.clone(),
+        }
+    }
+
+    pub fn current_ns(&self) -> String {
+        self.namespaces.borrow().current.clone()
+    }
+
+    //The scope top level forms in a namespace are evaluated in: the root for user,
+    //otherwise one holding the namespace's definitions just inside the root
+    pub fn namespace_env(&self, name: &str) -> Option<Environment> {
+        let root = self.get_root();
+        if name == DEFAULT_NS {
+            return Some(root);
+        }
+
+        let map = self.namespaces.borrow().table.get(name)?.map.clone();
+        Some(Environment {
+            map,
+            outer: Some(Rc::new(RefCell::new(root))),
+            dynamic: self.dynamic.clone(),
+            ns: Rc::new(name.to_string()),
+            namespaces: self.namespaces.clone(),
+        })
+    }
+
+    //Makes name the current namespace, creating it if needed, and sets *ns* to it
+    pub fn in_ns(&self, name: &str) {
+        {
+            let mut namespaces = self.namespaces.borrow_mut();
+            namespaces
+                .table
+                .entry(name.to_string())
+                .or_insert_with(|| Namespace {
+                    map: Rc::new(RefCell::new(HashMap::new())),
+                    aliases: HashMap::new(),
+                });
+            namespaces.current = name.to_string();
+        }
+        self.get_root()
+            .set("*ns*", MalType::symbol(name.to_string()));
+    }
+
+    //Copies the definitions of another namespace, or just the names given, into the current one;
+    //a snapshot, so later def!s there aren't seen until it's referred again
+    pub fn refer(&self, from: &str, only: Option<Vec<String>>) -> Result<(), MalType> {
+        let namespaces = self.namespaces.borrow();
+        let source = match namespaces.table.get(from) {
+            Some(ns) => ns.map.borrow().clone(),
+            None => return Err(MalType::error(format!("no namespace: {}", from))),
+        };
+        let target = namespaces.table[&namespaces.current].map.clone();
+
+        let names: Vec<String> = match only {
+            Some(names) => names,
+            None => source.keys().cloned().collect(),
+        };
+        for name in names {
+            match source.get(&name) {
+                Some(value) => {
+                    target.borrow_mut().insert(name, value.clone());
+                }
+                None => {
+                    return Err(MalType::error(format!("{}/{} not found.", from, name)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Lets the current namespace write short/name for full/name
+    pub fn alias(&self, short: &str, full: &str) -> Result<(), MalType> {
+        let mut namespaces = self.namespaces.borrow_mut();
+        if !namespaces.table.contains_key(full) {
+            return Err(MalType::error(format!("no namespace: {}", full)));
+        }
+        let current = namespaces.current.clone();
+        namespaces
+            .table
+            .get_mut(&current)
+            .unwrap()
+            .aliases
+            .insert(short.to_string(), full.to_string());
+        Ok(())
+    }
+
+    //Defines key in this scope's namespace as a var that binding can rebind
+    pub fn set_dynamic(&self, key: &str, value: MalType) -> MalType {
+        let map = match self.namespaces.borrow().table.get(&*self.ns) {
+            Some(ns) => ns.map.clone(),
+            None => self.get_root().map,
+        };
+        self.dynamic
+            .borrow_mut()
+            .insert(format!("{}/{}", self.ns, key));
+        map.borrow_mut().insert(key.to_string(), value.clone());
+        value
+    }
+
+    //The definitions holding the dynamic var key names from this scope, and its name
+    //there: ns/name or alias/name, else this namespace's own var, else user's
+    pub fn dynamic_var(&self, key: &str) -> Option<(Rc<RefCell<EnvScope>>, String)> {
+        let namespaces = self.namespaces.borrow();
+        let (ns, name) = match key.find('/') {
+            Some(slash) if slash > 0 && slash < key.len() - 1 => {
+                (self.full_ns(&namespaces, &key[..slash]), &key[slash + 1..])
+            }
+            _ => {
+                let own = namespaces
+                    .table
+                    .get(&*self.ns)
+                    .map_or(false, |ns| ns.map.borrow().contains_key(key));
+                (if own { self.ns.as_str() } else { DEFAULT_NS }, key)
+            }
+        };
+
+        if !self.dynamic.borrow().contains(&format!("{}/{}", ns, name)) {
+            return None;
         }
+        namespaces
+            .table
+            .get(ns)
+            .map(|n| (n.map.clone(), name.to_string()))
     }
 
     pub fn get_root(&self) -> Environment {
@@ -68,130 +281,335 @@ impl Environment {
         }
     }
 
+    //Binds function arguments to a parameter list, returning an error value if they don't fit
     pub fn bind_exprs(&mut self, binds: &[MalType], exprs: &[MalType]) -> MalType {
-        for (i, bind) in binds.iter().enumerate() {
-            if bind.is_symbol() {
-                let b = bind.get_string();
-                //println!("bind_exprs: {:?}", b);
-                if *b == "&" {
-                    if binds.len() > (i + 1) {
-                        //println!("bind_exprs: {:?}={:?}",binds[i+1],&exprs[i..]);
-                        if binds[i + 1].is_symbol() {
-                            let b2 = binds[i + 1].get_string();
-                            if exprs.len() > i {
-                                //println!("{:?}", exprs.len() > i);
-                                //println!("{:?}", exprs.len());
-                                //println!("{:?}", i);
-                                //println!("{:?}", exprs[i..].to_vec());
-                                self.set(&b2, MalType::list(exprs[i..].to_vec()));
-                            } else {
-                                self.set(&b2, MalType::list(Vec::new()));
-                            }
-                        }
-                        break;
+        match self.bind_seq(binds, exprs, &MalType::list(exprs.to_vec())) {
+            Ok(()) => MalType::nil(),
+            Err(e) => e,
+        }
+    }
+
+    //Binds a symbol, or destructures value into a sequential [a b & rest :as all]
+    //or associative {:keys [a b] :or {a 1} :as m} binding form
+    pub fn bind(&mut self, pattern: &MalType, value: MalType) -> Result<(), MalType> {
+        if pattern.is_symbol() {
+            self.set(&pattern.get_string(), value);
+            Ok(())
+        } else if pattern.is_list() || pattern.is_vector() {
+            let items = if value.is_list() || value.is_vector() {
+                value.get_list().to_vec()
+            } else if value.is_nil() {
+                Vec::new()
+            } else {
+                return Err(MalType::error(format!(
+                    "can't destructure {} as a sequence",
+                    pr_str(&value, true)
+                )));
+            };
+            self.bind_seq(&pattern.get_list(), &items, &value)
+        } else if pattern.is_map() {
+            self.bind_map(&pattern.get_list(), &value)
+        } else {
+            Err(MalType::error(format!(
+                "unsupported binding form: {}",
+                pr_str(pattern, true)
+            )))
+        }
+    }
+
+    fn bind_seq(
+        &mut self,
+        patterns: &[MalType],
+        items: &[MalType],
+        whole: &MalType,
+    ) -> Result<(), MalType> {
+        let mut i = 0;
+        let mut n = 0;
+        let mut rest_bound = false;
+
+        while i < patterns.len() {
+            let p = &patterns[i];
+            if p.is_keyword() && *p.get_string() == ":as" {
+                match patterns.get(i + 1) {
+                    Some(name) if name.is_symbol() => self.set(&name.get_string(), whole.clone()),
+                    _ => {
+                        return Err(MalType::error(
+                            ":as must be followed by a symbol".to_string(),
+                        ))
+                    }
+                };
+                i += 2;
+            } else if rest_bound {
+                return Err(MalType::error(format!(
+                    "only :as can follow & rest in {}",
+                    pr_str(&MalType::vector(patterns.to_vec()), true)
+                )));
+            } else if p.is_symbol() && *p.get_string() == "&" {
+                let rest = match patterns.get(i + 1) {
+                    Some(r) => r,
+                    None => {
+                        return Err(MalType::error(
+                            "& must be followed by a binding".to_string(),
+                        ))
                     }
+                };
+                let rest_items = if n < items.len() {
+                    items[n..].to_vec()
                 } else {
-                    //println!("bind_exprs: setting");
-                    if exprs.len() > i {
-                        self.set(&b, exprs[i].clone());
+                    Vec::new()
+                };
+                //a map after & takes keyword arguments, or a single map
+                let rest_value = if !rest.is_map() {
+                    MalType::list(rest_items)
+                } else if rest_items.len() == 1 && rest_items[0].is_map() {
+                    rest_items[0].clone()
+                } else if rest_items.len() % 2 == 0 {
+                    MalType::map(rest_items)
+                } else {
+                    return Err(MalType::error(format!(
+                        "keyword arguments need a value for each key: {}",
+                        pr_str(&MalType::list(rest_items), true)
+                    )));
+                };
+                self.bind(rest, rest_value)?;
+                rest_bound = true;
+                i += 2;
+            } else {
+                self.bind(p, items.get(n).cloned().unwrap_or_else(MalType::nil))?;
+                n += 1;
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn bind_map(&mut self, entries: &[MalType], value: &MalType) -> Result<(), MalType> {
+        let pairs = if value.is_map() {
+            value.get_list().to_vec()
+        } else if value.is_nil() {
+            Vec::new()
+        } else {
+            return Err(MalType::error(format!(
+                "can't destructure {} as a map",
+                pr_str(value, true)
+            )));
+        };
+        if entries.len() % 2 == 1 {
+            return Err(MalType::error(
+                "map binding forms need an even number of forms".to_string(),
+            ));
+        }
+
+        let lookup = |key: &MalType| {
+            pairs
+                .chunks(2)
+                .find(|kv| kv.len() == 2 && kv[0] == *key)
+                .map(|kv| kv[1].clone())
+        };
+        let defaults = match entries
+            .chunks(2)
+            .find(|e| e[0].is_keyword() && *e[0].get_string() == ":or")
+        {
+            Some(e) if e[1].is_map() => e[1].get_list().to_vec(),
+            Some(_) => return Err(MalType::error(":or takes a map of defaults".to_string())),
+            None => Vec::new(),
+        };
+
+        //the value for key, else the :or default for name evaluated here, else nil
+        let lookup_or_default = |env: &mut Environment, key: &MalType, name: &MalType| {
+            if let Some(v) = lookup(key) {
+                return Ok(v);
+            }
+            match defaults.chunks(2).find(|d| d.len() == 2 && d[0] == *name) {
+                Some(d) => {
+                    let v = eval(&d[1], env);
+                    if v.is_error() {
+                        Err(v)
+                    } else {
+                        Ok(v)
                     }
                 }
+                None => Ok(MalType::nil()),
+            }
+        };
+
+        for entry in entries.chunks(2) {
+            let (k, v) = (&entry[0], &entry[1]);
+            let directive = if k.is_keyword() {
+                k.get_string().to_string()
             } else {
-                return MalType::error("Non Symbol in parameter list".to_string());
+                String::new()
+            };
+
+            match directive.as_str() {
+                ":keys" | ":strs" | ":syms" => {
+                    if !(v.is_list() || v.is_vector()) {
+                        return Err(MalType::error(format!(
+                            "{} takes a vector of symbols",
+                            directive
+                        )));
+                    }
+                    for name in v.get_list().iter() {
+                        if !name.is_symbol() {
+                            return Err(MalType::error(format!(
+                                "{} takes a vector of symbols, got {}",
+                                directive,
+                                pr_str(name, true)
+                            )));
+                        }
+                        let s = name.get_string().to_string();
+                        let key = match directive.as_str() {
+                            ":keys" => MalType::keyword(format!(":{}", s)),
+                            ":strs" => MalType::string(s.clone()),
+                            _ => MalType::symbol(s.clone()),
+                        };
+                        let value = lookup_or_default(self, &key, name)?;
+                        self.set(&s, value);
+                    }
+                }
+                ":or" => {}
+                ":as" => self.bind(v, value.clone())?,
+                _ => {
+                    let value = lookup_or_default(self, v, k)?;
+                    self.bind(k, value)?;
+                }
             }
         }
-        MalType::nil()
+
+        Ok(())
     }
 }
 
-fn new_let_env(bind_list: &MalType, env: &mut Environment) -> Option<Environment> {
+fn new_let_env(bind_list: &MalType, env: &mut Environment) -> Result<Environment, MalType> {
     let mut new_env = env.get_inner();
     if bind_list.is_list() || bind_list.is_vector() {
         let l = bind_list.get_list();
         if l.len() % 2 == 1 {
-            return None;
+            return Err(MalType::error(
+                "let* requires an even number of binding forms".to_string(),
+            ));
         }
         for chunk in l.chunks(2) {
-            if !chunk.is_empty() {
-                if chunk[0].is_symbol() {
-                    let sym = chunk[0].get_string();
-                    let three = eval(&chunk[1], &mut new_env);
-                    if three.is_error() {
-                        return None;
-                    } else {
-                        new_env.set(&sym, three);
-                    }
-                } else {
-                    return None;
-                }
+            let value = eval(&chunk[1], &mut new_env);
+            if value.is_error() {
+                return Err(value);
             }
+            new_env.bind(&chunk[0], value)?;
         }
     } else {
-        return None;
+        return Err(MalType::error(
+            "let* bindings must be a list or vector".to_string(),
+        ));
     }
 
-    Some(new_env)
+    Ok(new_env)
+}
+
+//Symbols ending in # within one quasiquote, like x#, all become the same fresh
+//symbol so the names a macro binds can't capture or shadow the caller's, and
+//names defined in the namespace it's written in, user included, are qualified
+//so a macro's expansion refers to them from any namespace
+pub fn quasiquote(ast: &MalType, env: &Environment) -> MalType {
+    quasiquote_gensyms(ast, env, &mut HashMap::new())
 }
 
-pub fn is_pair(ast: &MalType) -> bool {
-    if ast.is_list() || ast.is_vector() {
-        !ast.get_list().is_empty()
+fn quote_symbol(
+    ast: &MalType,
+    env: &Environment,
+    gensyms: &mut HashMap<String, MalType>,
+) -> MalType {
+    let name = ast.get_string();
+    if name.len() > 1 && name.ends_with('#') {
+        gensyms
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let sym = core::gensym(&format!("{}__", &name[..name.len() - 1]));
+                MalType::symbol(format!("{}__auto__", sym.get_string()))
+            })
+            .clone()
+    } else if !name.contains('/') && {
+        let namespaces = env.namespaces.borrow();
+        namespaces
+            .table
+            .get(&*env.ns)
+            .map_or(false, |ns| ns.map.borrow().contains_key(&*name))
+    } {
+        MalType::symbol(format!("{}/{}", env.ns, name))
     } else {
-        false
+        ast.clone()
     }
 }
 
-pub fn quasiquote(ast: &MalType) -> MalType {
-    if is_pair(ast) {
-        let l = ast.get_list();
-        if l.is_empty() {
-            return MalType::nil();
-        }
-
-        if l[0].is_symbol() {
-            let sym = l[0].get_string();
-            if l.len() == 1 {
-                return MalType::nil();
-            }
-
-            if *sym == "unquote" {
-                return l[1].clone();
+//The name def! and friends define: syntax-quote may have qualified it with the
+//namespace being defined in, but a def! can't reach into another namespace
+fn def_name(sym: &MalType, env: &Environment) -> Result<String, MalType> {
+    let name = sym.get_string();
+    match name.find('/') {
+        Some(i) if i > 0 && i < name.len() - 1 => {
+            if name[..i] == **env.ns {
+                Ok(name[i + 1..].to_string())
+            } else {
+                Err(MalType::error(format!(
+                    "can't define {} in namespace {}",
+                    name, env.ns
+                )))
             }
         }
+        _ => Ok(name.to_string()),
+    }
+}
 
-        if is_pair(&l[0]) {
-            let l2 = l[0].get_list();
-            if l2.is_empty() {
-                return MalType::nil();
-            }
-
-            if l2[0].is_symbol() {
-                let sym2 = l2[0].get_string();
-                if l2.len() == 1 {
-                    return MalType::nil();
-                }
-
-                if *sym2 == "splice-unquote" {
-                    let mut list: Vec<MalType> = Vec::new();
-                    list.push(MalType::symbol("concat".to_string()));
-                    list.push(l2[1].clone());
-                    list.push(quasiquote(&MalType::list(l[1..].to_vec())));
-                    return MalType::list(list);
-                }
-            }
+fn quasiquote_gensyms(
+    ast: &MalType,
+    env: &Environment,
+    gensyms: &mut HashMap<String, MalType>,
+) -> MalType {
+    let symbol = |name: &str| MalType::symbol(name.to_string());
+    if ast.is_list() {
+        let l = ast.get_list();
+        if l.len() == 2 && l[0].is_symbol() && *l[0].get_string() == "unquote" {
+            l[1].clone()
+        } else {
+            quasiquote_seq(&l, env, gensyms)
         }
-
-        let mut list: Vec<MalType> = Vec::new();
-        list.push(MalType::symbol("cons".to_string()));
-        list.push(quasiquote(&l[0]));
-        list.push(quasiquote(&MalType::list(l[1..].to_vec())));
-        return MalType::list(list);
+    } else if ast.is_vector() {
+        let l = ast.get_list();
+        MalType::list(vec![symbol("vec"), quasiquote_seq(&l, env, gensyms)])
+    } else if ast.is_map() {
+        let mut list = vec![symbol("hash-map")];
+        for item in ast.get_list().iter() {
+            list.push(quasiquote_gensyms(item, env, gensyms));
+        }
+        MalType::list(list)
+    } else if ast.is_symbol() {
+        MalType::list(vec![symbol("quote"), quote_symbol(ast, env, gensyms)])
     } else {
-        let mut list: Vec<MalType> = Vec::new();
-        list.push(MalType::symbol("quote".to_string()));
-        list.push(ast.clone());
-        return MalType::list(list);
+        ast.clone()
+    }
+}
+
+//Builds the elements back up from the end, consing each one on
+//or concatenating the sequence a ~@ form evaluates to
+fn quasiquote_seq(
+    l: &[MalType],
+    env: &Environment,
+    gensyms: &mut HashMap<String, MalType>,
+) -> MalType {
+    let mut result = MalType::list(Vec::new());
+    for item in l.iter().rev() {
+        let splice = item.is_list() && {
+            let l2 = item.get_list();
+            l2.len() == 2 && l2[0].is_symbol() && *l2[0].get_string() == "splice-unquote"
+        };
+        let (f, arg) = if splice {
+            ("concat", item.get_list()[1].clone())
+        } else {
+            ("cons", quasiquote_gensyms(item, env, gensyms))
+        };
+        result = MalType::list(vec![MalType::symbol(f.to_string()), arg, result]);
     }
+    result
 }
 
 fn is_macro_call(ast: &MalType, env: &mut Environment) -> bool {
@@ -208,41 +626,486 @@ fn is_macro_call(ast: &MalType, env: &mut Environment) -> bool {
     false
 }
 
-fn macroexpand(ast_incomming: &MalType, env: &mut Environment) -> MalType {
+//One step of expansion when ast is a macro call, otherwise ast itself;
+//with *trace-macros* set each step is logged to stderr
+pub fn macroexpand_1(ast: &MalType, env: &mut Environment) -> MalType {
+    if !is_macro_call(ast, env) {
+        return ast.clone();
+    }
+
+    let l = ast.get_list();
+    let val = env.get(&l[0].get_string());
+    let expanded = if val.is_func() {
+        let (f, _) = val.get_func();
+        f(l[1..].to_vec())
+    } else {
+        let (_, _, f, _) = val.get_func_tco();
+        f(l[1..].to_vec())
+    };
+
+    let trace = env.get_root().get("*trace-macros*");
+    if !trace.is_error() && !trace.is_nil() && !(trace.is_bool() && !trace.get_bool()) {
+        eprintln!(
+            "macroexpand: {}\n  => {}",
+            pr_str(ast, true),
+            pr_str(&expanded, true)
+        );
+    }
+    expanded
+}
+
+pub fn macroexpand(ast_incomming: &MalType, env: &mut Environment) -> MalType {
     let mut ast = ast_incomming.clone();
-    let mut is_macro = is_macro_call(&ast, env);
+    while is_macro_call(&ast, env) {
+        ast = macroexpand_1(&ast, env);
+    }
+
+    ast
+}
+
+//Expands macro calls in every subform, leaving quoted data, binding names and
+//parameter lists alone and only descending into a quasiquote's unquotes
+pub fn macroexpand_all(ast: &MalType, env: &mut Environment) -> MalType {
+    match expand_form(ast, env) {
+        Ok(expanded) => expanded,
+        Err(e) => e,
+    }
+}
 
-    while is_macro {
+fn expand_form(ast: &MalType, env: &mut Environment) -> Result<MalType, MalType> {
+    let ast = macroexpand(ast, env);
+    if ast.is_error() {
+        Err(ast)
+    } else if ast.is_vector() {
+        expand_each(&ast.get_list(), env).map(MalType::vector)
+    } else if ast.is_map() {
+        let entries = ast.get_list();
+        let mut expanded = Vec::new();
+        for pair in entries.chunks(2) {
+            expanded.push(pair[0].clone());
+            expanded.push(expand_form(&pair[1], env)?);
+        }
+        Ok(MalType::map(expanded))
+    } else if !ast.is_list() || ast.get_list().is_empty() || !ast.get_list()[0].is_symbol() {
+        if ast.is_list() {
+            expand_each(&ast.get_list(), env).map(MalType::list)
+        } else {
+            Ok(ast)
+        }
+    } else {
         let l = ast.get_list();
-        if l[0].is_symbol() {
-            let sym = l[0].get_string();
-            let val = env.get(&sym);
-            if val.is_func() {
-                let (f, _) = val.get_func();
-                ast = f(l[1..].to_vec());
-            } else if val.is_func_tco() {
-                let (_, _, _, f, _) = val.get_func_tco();
-                ast = f(l[1..].to_vec());
+        let head = l[0].get_string();
+        match head.as_str() {
+            //these take code as it's written rather than evaluating it
+            "quote" | "macroexpand" | "macroexpand-1" | "macroexpand-all" | "quasiquoteexpand" => {
+                Ok(ast.clone())
+            }
+            "quasiquote" if l.len() > 1 => Ok(MalType::list(vec![
+                l[0].clone(),
+                expand_unquotes(&l[1], env)?,
+            ])),
+            "fn*" if l.len() > 1 => {
+                if l[1..].iter().all(is_arity_clause) {
+                    let mut clauses = vec![l[0].clone()];
+                    for clause in l[1..].iter() {
+                        let c = clause.get_list();
+                        let mut expanded = vec![c[0].clone()];
+                        expanded.extend(expand_each(&c[1..], env)?.into_iter());
+                        clauses.push(MalType::list(expanded));
+                    }
+                    Ok(MalType::list(clauses))
+                } else {
+                    keep_then_expand(&l, 2, env)
+                }
+            }
+            "let*" | "loop*" if l.len() > 1 && (l[1].is_list() || l[1].is_vector()) => {
+                let mut bindings = Vec::new();
+                for pair in l[1].get_list().chunks(2) {
+                    bindings.push(pair[0].clone());
+                    if pair.len() > 1 {
+                        bindings.push(expand_form(&pair[1], env)?);
+                    }
+                }
+                let bindings = if l[1].is_vector() {
+                    MalType::vector(bindings)
+                } else {
+                    MalType::list(bindings)
+                };
+                let mut expanded = vec![l[0].clone(), bindings];
+                expanded.extend(expand_each(&l[2..], env)?.into_iter());
+                Ok(MalType::list(expanded))
             }
-            is_macro = is_macro_call(&ast, env);
+            "def!" | "defmacro!" => keep_then_expand(&l, 2, env),
+            _ => expand_each(&l, env).map(MalType::list),
         }
     }
+}
 
-    ast
+fn expand_each(forms: &[MalType], env: &mut Environment) -> Result<Vec<MalType>, MalType> {
+    let mut expanded = Vec::new();
+    for form in forms.iter() {
+        expanded.push(expand_form(form, env)?);
+    }
+    Ok(expanded)
+}
+
+//The first count elements of a special form as they are, the rest expanded
+fn keep_then_expand(
+    l: &[MalType],
+    count: usize,
+    env: &mut Environment,
+) -> Result<MalType, MalType> {
+    let count = count.min(l.len());
+    let mut expanded = l[..count].to_vec();
+    expanded.extend(expand_each(&l[count..], env)?.into_iter());
+    Ok(MalType::list(expanded))
+}
+
+fn expand_unquotes(ast: &MalType, env: &mut Environment) -> Result<MalType, MalType> {
+    if !ast.is_list() && !ast.is_vector() && !ast.is_map() {
+        return Ok(ast.clone());
+    }
+
+    let l = ast.get_list();
+    let unquote = l.len() == 2
+        && l[0].is_symbol()
+        && (*l[0].get_string() == "unquote" || *l[0].get_string() == "splice-unquote");
+    let mut expanded = Vec::new();
+    if ast.is_list() && unquote {
+        expanded.push(l[0].clone());
+        expanded.push(expand_form(&l[1], env)?);
+    } else {
+        for item in l.iter() {
+            expanded.push(expand_unquotes(item, env)?);
+        }
+    }
+
+    if ast.is_vector() {
+        Ok(MalType::vector(expanded))
+    } else if ast.is_map() {
+        Ok(MalType::map(expanded))
+    } else {
+        Ok(MalType::list(expanded))
+    }
+}
+
+//Special forms that take exactly one form, as written
+const ONE_FORM_SPECIALS: [&str; 4] = [
+    "macroexpand",
+    "macroexpand-1",
+    "macroexpand-all",
+    "quasiquoteexpand",
+];
+
+//Several body forms are evaluated as an implicit do
+fn implicit_do(forms: &[MalType]) -> MalType {
+    match forms.len() {
+        0 => MalType::nil(),
+        1 => forms[0].clone(),
+        _ => {
+            let mut l = vec![MalType::symbol("do".to_string())];
+            l.extend_from_slice(forms);
+            MalType::list(l)
+        }
+    }
+}
+
+//A ([params] body...) clause of a multi-arity fn*
+fn is_arity_clause(form: &MalType) -> bool {
+    form.is_list() && form.get_list().first().map_or(false, |p| p.is_vector())
+}
+
+//Parses what follows fn*: either one `params body...` clause, or for a multi-arity
+//function several `([params] body...)` clauses
+fn parse_arities(forms: &[MalType]) -> Result<Vec<Arity>, MalType> {
+    let clauses: Vec<Vec<MalType>> = if !forms.is_empty() && forms.iter().all(is_arity_clause) {
+        forms.iter().map(|f| f.get_list().to_vec()).collect()
+    } else {
+        vec![forms.to_vec()]
+    };
+
+    let mut arities: Vec<Arity> = Vec::new();
+    for clause in clauses {
+        match clause.first() {
+            Some(p) if p.is_list() || p.is_vector() => (),
+            Some(p) => {
+                return Err(MalType::error(format!(
+                    "bind list is not a list: {} ",
+                    pr_str(p, true)
+                )))
+            }
+            None => return Err(MalType::error("fn* is missing a bind list".to_string())),
+        }
+
+        let arity = Arity {
+            params: clause[0].get_list().to_vec(),
+            body: implicit_do(&clause[1..]),
+        };
+
+        let (fixed, variadic) = arity.required();
+        for other in &arities {
+            let (other_fixed, other_variadic) = other.required();
+            if variadic && other_variadic {
+                return Err(MalType::error(
+                    "fn* can't have more than one variadic arity".to_string(),
+                ));
+            } else if !variadic && !other_variadic && fixed == other_fixed {
+                return Err(MalType::error(format!(
+                    "fn* can't have two arities taking {} arguments",
+                    fixed
+                )));
+            }
+        }
+        arities.push(arity);
+    }
+
+    Ok(arities)
+}
+
+//Picks the arity taking this many arguments, preferring an exact one over a variadic one
+fn select_arity(arities: &[Arity], nargs: usize) -> Result<&Arity, MalType> {
+    arities
+        .iter()
+        .find(|a| a.required() == (nargs, false))
+        .or_else(|| arities.iter().find(|a| a.accepts(nargs)))
+        .ok_or_else(|| {
+            let expected: Vec<String> = arities
+                .iter()
+                .map(|a| match a.required() {
+                    (n, true) => format!("{}+", n),
+                    (n, false) => n.to_string(),
+                })
+                .collect();
+            MalType::error(format!(
+                "wrong number of arguments ({}), expected {}",
+                nargs,
+                expected.join(" or ")
+            ))
+        })
+}
+
+//Binds args in a new scope of the function's environment, returning the body to evaluate there
+fn bind_arity(
+    arities: &[Arity],
+    env: &Environment,
+    args: &[MalType],
+) -> Result<(MalType, Environment), MalType> {
+    let arity = select_arity(arities, args.len())?;
+    let mut new_func_env = env.get_inner();
+    let bound = new_func_env.bind_exprs(&arity.params, args);
+    if bound.is_error() {
+        return Err(bound);
+    }
+    Ok((arity.body.clone(), new_func_env))
+}
+
+//Makes sure every recur in a macroexpanded loop* body is in tail position, so jumping
+//back never leaves work unfinished; a nested loop* checks its own body when evaluated
+fn check_recur(form: &MalType, tail: bool) -> Result<(), MalType> {
+    if !form.is_list() && !form.is_vector() && !form.is_map() {
+        return Ok(());
+    }
+
+    let l = form.get_list();
+    if !form.is_list() || l.is_empty() || !l[0].is_symbol() {
+        return check_recur_all(&l, false);
+    }
+
+    let head = l[0].get_string();
+    match head.as_str() {
+        "quote" | "quasiquote" => Ok(()),
+        "recur" if !tail => Err(MalType::error(format!(
+            "recur must be in tail position: {}",
+            pr_str(form, true)
+        ))),
+        "do" => check_recur_all(&l[1..], tail),
+        "if" if l.len() > 1 => {
+            check_recur(&l[1], false)?;
+            for branch in l[2..].iter() {
+                check_recur(branch, tail)?;
+            }
+            Ok(())
+        }
+        "let*" | "loop*" if l.len() > 1 => {
+            if !l[1].is_list() && !l[1].is_vector() {
+                return Err(MalType::error(format!(
+                    "{} bindings must be a list or vector",
+                    head
+                )));
+            }
+            let bindings = l[1].get_list();
+            let values: Vec<MalType> = bindings.iter().skip(1).step_by(2).cloned().collect();
+            check_recur_all(&values, false)?;
+            if *head == "let*" {
+                check_recur_all(&l[2..], tail)
+            } else {
+                Ok(())
+            }
+        }
+        //a function body has no loop* of its own to recur to
+        "fn*" => match parse_arities(&l[1..]) {
+            Ok(arities) => {
+                for arity in arities {
+                    check_recur(&arity.body, false)?;
+                }
+                Ok(())
+            }
+            Err(_) => Ok(()),
+        },
+        _ => check_recur_all(&l[1..], false),
+    }
 }
 
+//Checks a sequence of forms where only the last one can be in tail position
+fn check_recur_all(forms: &[MalType], tail: bool) -> Result<(), MalType> {
+    for (i, item) in forms.iter().enumerate() {
+        check_recur(item, tail && i == forms.len() - 1)?;
+    }
+    Ok(())
+}
+
+//(binding [var value ...] body...) evaluates body with dynamic vars set to new
+//values in their namespaces, putting the old ones back whether or not it errors
+fn eval_binding(forms: &[MalType], env: &mut Environment) -> MalType {
+    let bindings = match forms.first() {
+        Some(b) if (b.is_list() || b.is_vector()) && b.get_list().len() % 2 == 0 => b.get_list(),
+        _ => return MalType::error("binding requires a vector of var and value pairs".to_string()),
+    };
+
+    let mut values = Vec::new();
+    for pair in bindings.chunks(2) {
+        let var = if pair[0].is_symbol() {
+            env.dynamic_var(&pair[0].get_string())
+        } else {
+            None
+        };
+        let (scope, name) = match var {
+            Some(var) => var,
+            None => {
+                return MalType::error(format!(
+                    "can't dynamically bind non-dynamic var: {}",
+                    pr_str(&pair[0], true)
+                ))
+            }
+        };
+        let value = eval(&pair[1], env);
+        if value.is_error() {
+            return value;
+        }
+        values.push((scope, name, value));
+    }
+
+    let mut saved = Vec::new();
+    for (scope, name, value) in values {
+        let old = scope.borrow_mut().insert(name.clone(), value);
+        saved.push((scope, name, old.unwrap_or_else(MalType::nil)));
+    }
+
+    let result = eval(&implicit_do(&forms[1..]), env);
+
+    for (scope, name, value) in saved.into_iter().rev() {
+        scope.borrow_mut().insert(name, value);
+    }
+    result
+}
+
+//(with-open [name handle ...] body...) evaluates body with the handles bound, then
+//closes them last to first whether or not it errors, so buffered writes aren't lost
+fn eval_with_open(forms: &[MalType], env: &mut Environment) -> MalType {
+    let bindings = match forms.first() {
+        Some(b) if (b.is_list() || b.is_vector()) && b.get_list().len() % 2 == 0 => b.get_list(),
+        _ => {
+            return MalType::error(
+                "with-open requires a vector of name and handle pairs".to_string(),
+            )
+        }
+    };
+
+    let mut scope = env.get_inner();
+    let mut handles = Vec::new();
+    let mut result = MalType::nil();
+    for pair in bindings.chunks(2) {
+        let value = eval(&pair[1], &mut scope);
+        if value.is_error() {
+            result = value;
+            break;
+        } else if !value.is_handle() {
+            result = MalType::error(format!(
+                "with-open: {} is not a handle",
+                pr_str(&value, true)
+            ));
+            break;
+        }
+        handles.push(value.get_handle());
+        if let Err(e) = scope.bind(&pair[0], value) {
+            result = e;
+            break;
+        }
+    }
+
+    if !result.is_error() {
+        result = eval(&implicit_do(&forms[1..]), &mut scope);
+    }
+
+    for h in handles.iter().rev() {
+        let closed = core::close_handle(h);
+        if closed.is_error() && !result.is_error() {
+            result = closed;
+        }
+    }
+    result
+}
+
+//Evaluates t1, or errors once evaluation nests deeper than *max-depth*
+//instead of overflowing the native stack
 pub fn eval(t1: &MalType, env: &mut Environment) -> MalType {
+    //evaluating in the root means at the top level of the current namespace
+    if env.outer.is_none() && env.current_ns() != DEFAULT_NS {
+        if let Some(mut ns_env) = env.namespace_env(&env.current_ns()) {
+            return eval(t1, &mut ns_env);
+        }
+    }
+
+    let depth = DEPTH.with(|d| d.get());
+    if depth == 0 {
+        let max = env.get_root().get("*max-depth*");
+        let max = if max.is_int() && max.get_int() > 0 {
+            (max.get_int() as usize).min(MAX_DEPTH_CEILING)
+        } else {
+            DEFAULT_MAX_DEPTH
+        };
+        MAX_DEPTH.with(|m| m.set(max));
+    }
+
+    let max = MAX_DEPTH.with(|m| m.get());
+    if depth >= max {
+        return MalType::error(format!("stack depth exceeded ({})", max));
+    }
+
+    DEPTH.with(|d| d.set(depth + 1));
+    let result = eval_form(t1, env);
+    DEPTH.with(|d| d.set(depth));
+    result
+}
+
+fn eval_form(t1: &MalType, env: &mut Environment) -> MalType {
     let mut ast = t1.clone();
     let mut eval_env: Environment = env.clone();
+    //the bindings, body and outer environment of the loop* a recur jumps back to
+    let mut recur_target: Option<(Vec<MalType>, MalType, Environment)> = None;
 
     //println!("eval {:?}", ast);
 
     loop {
+        if core::exit_status().is_some() {
+            return MalType::nil();
+        }
+
         if !ast.is_list() {
             return eval_ast(&ast, &mut eval_env);
         }
 
-        ast = macroexpand(&ast, env);
+        ast = macroexpand(&ast, &mut eval_env);
 
         if ast.is_error() {
             return ast;
@@ -267,32 +1130,135 @@ pub fn eval(t1: &MalType, env: &mut Environment) -> MalType {
                     //println!("in eval after eval: {:?}", eval_result);
                     return eval_result;
                 } else if *s == "def!" {
-                    let second = &uneval_list[1];
+                    let second = match def_name(&uneval_list[1], &eval_env) {
+                        Ok(name) => name,
+                        Err(e) => return e,
+                    };
                     let third = eval(&uneval_list[2], &mut eval_env);
                     if third.is_error() {
                         return third;
                     } else {
-                        return eval_env.set(&second.get_string(), third);
+                        return eval_env.set(&second, third);
+                    }
+                } else if *s == "def-dynamic!" {
+                    if uneval_list.len() != 3 || !uneval_list[1].is_symbol() {
+                        return MalType::error(
+                            "def-dynamic! takes a symbol and a value".to_string(),
+                        );
+                    }
+                    let name = match def_name(&uneval_list[1], &eval_env) {
+                        Ok(name) => name,
+                        Err(e) => return e,
+                    };
+                    let value = eval(&uneval_list[2], &mut eval_env);
+                    if value.is_error() {
+                        return value;
                     }
+                    return eval_env.set_dynamic(&name, value);
+                } else if *s == "binding" {
+                    return eval_binding(&uneval_list[1..], &mut eval_env);
+                } else if *s == "with-open" {
+                    return eval_with_open(&uneval_list[1..], &mut eval_env);
                 } else if *s == "defmacro!" {
-                    let second = &uneval_list[1];
+                    let second = match def_name(&uneval_list[1], &eval_env) {
+                        Ok(name) => name,
+                        Err(e) => return e,
+                    };
                     let mut func = eval(&uneval_list[2], &mut eval_env);
                     func.set_is_macro(true);
                     if func.is_error() {
                         return func;
                     } else {
-                        return eval_env.set(&second.get_string(), func);
+                        return eval_env.set(&second, func);
                     }
+                } else if ONE_FORM_SPECIALS.contains(&s.as_str()) && uneval_list.len() != 2 {
+                    return MalType::error(format!(
+                        "{} expects 1 argument, got {}",
+                        s,
+                        uneval_list.len() - 1
+                    ));
                 } else if *s == "macroexpand" {
-                    return macroexpand(&uneval_list[1], env);
+                    return macroexpand(&uneval_list[1], &mut eval_env);
+                } else if *s == "macroexpand-1" {
+                    return macroexpand_1(&uneval_list[1], &mut eval_env);
+                } else if *s == "macroexpand-all" {
+                    return macroexpand_all(&uneval_list[1], &mut eval_env);
                 } else if *s == "let*" {
-                    eval_env = new_let_env(&uneval_list[1], &mut eval_env).unwrap();
+                    match new_let_env(&uneval_list[1], &mut eval_env) {
+                        Ok(new_env) => eval_env = new_env,
+                        Err(e) => return e,
+                    }
                     ast = uneval_list[2].clone();
+                } else if *s == "loop*" {
+                    if uneval_list.len() < 2 {
+                        return MalType::error("loop* is missing its bindings".to_string());
+                    }
+                    //expand once, so checking and running the loop share one expansion
+                    let expanded = match expand_form(&ast, &mut eval_env) {
+                        Ok(form) => form.get_list(),
+                        Err(e) => return e,
+                    };
+                    let body = implicit_do(&expanded[2..]);
+                    //the loop* form itself only checks its binding values
+                    let checked = check_recur(&MalType::list(expanded.to_vec()), false)
+                        .and_then(|_| check_recur(&body, true));
+                    if let Err(e) = checked {
+                        return e;
+                    }
+
+                    let loop_env = eval_env.clone();
+                    match new_let_env(&expanded[1], &mut eval_env) {
+                        Ok(new_env) => eval_env = new_env,
+                        Err(e) => return e,
+                    }
+                    let params = expanded[1].get_list().iter().step_by(2).cloned().collect();
+                    recur_target = Some((params, body.clone(), loop_env));
+                    ast = body;
+                } else if *s == "recur" {
+                    //check_recur has made sure this is in tail position, so the
+                    //loop* is in this same call of eval and jumping back is just a rebind
+                    let (params, body, loop_env) = match recur_target {
+                        Some(ref target) => target.clone(),
+                        None => {
+                            return MalType::error(
+                                "recur must be in tail position of loop*".to_string(),
+                            )
+                        }
+                    };
+                    if uneval_list.len() - 1 != params.len() {
+                        return MalType::error(format!(
+                            "recur expects {} arguments, got {}",
+                            params.len(),
+                            uneval_list.len() - 1
+                        ));
+                    }
+
+                    let mut values = Vec::new();
+                    for arg in uneval_list[1..].iter() {
+                        let value = eval(arg, &mut eval_env);
+                        if value.is_error() {
+                            return value;
+                        }
+                        values.push(value);
+                    }
+                    let mut new_env = loop_env.get_inner();
+                    for (param, value) in params.iter().zip(values) {
+                        if let Err(e) = new_env.bind(param, value) {
+                            return e;
+                        }
+                    }
+                    eval_env = new_env;
+                    ast = body;
                 } else if *s == "quote" {
                     return uneval_list[1].clone();
+                } else if *s == "quasiquoteexpand" {
+                    return quasiquote(&uneval_list[1], &eval_env);
                 } else if *s == "quasiquote" {
-                    ast = quasiquote(&uneval_list[1]);
+                    ast = quasiquote(&uneval_list[1], &eval_env);
                 } else if *s == "do" {
+                    if uneval_list.len() == 1 {
+                        return MalType::nil();
+                    }
                     let temp = eval_ast(
                         &MalType::list(uneval_list[1..uneval_list.len() - 1].to_vec()),
                         &mut eval_env,
@@ -322,40 +1288,30 @@ pub fn eval(t1: &MalType, env: &mut Environment) -> MalType {
                         }
                     }
                 } else if *s == "fn*" {
-                    if uneval_list[1].is_list() || uneval_list[1].is_vector() {
-                        let binds = &*uneval_list[1].get_list();
-                        //need to clone everything to prevent dangaling references
-                        let binds_clone = binds.clone();
-                        let function_body = uneval_list[2].clone();
-
-                        //create new clone environment, to cut ties to passed in env
-                        let new_env = eval_env.clone();
-
-                        //println!("{:?}", function_body);
-                        let new_func = move |args: BuiltinFuncArgs| {
-                            //println!("do_fn_special_atom: {:?}", args);
-                            //clone again to gut ties to outer function body
-                            let mut new_func_env = new_env.get_inner();
-
-                            //bind function arguments
-                            new_func_env.bind_exprs(&binds_clone, &args);
-
-                            //finally call the function
-                            eval(&function_body, &mut new_func_env)
-                        };
-                        return MalType::func_tco(
-                            binds.clone(),
-                            Box::new(uneval_list[2].clone()),
-                            eval_env.clone(),
-                            Rc::new(Box::new(new_func)),
-                            false,
-                        );
-                    } else {
-                        return MalType::error(format!(
-                            "bind list is not a list: {} ",
-                            pr_str(&uneval_list[1], true)
-                        ));
-                    }
+                    let arities = match parse_arities(&uneval_list[1..]) {
+                        Ok(arities) => arities,
+                        Err(e) => return e,
+                    };
+                    //need to clone everything to prevent dangaling references
+                    let arities_clone = arities.clone();
+
+                    //create new clone environment, to cut ties to passed in env
+                    let new_env = eval_env.clone();
+
+                    let new_func = move |args: BuiltinFuncArgs| match bind_arity(
+                        &arities_clone,
+                        &new_env,
+                        &args,
+                    ) {
+                        Ok((body, mut new_func_env)) => eval(&body, &mut new_func_env),
+                        Err(e) => e,
+                    };
+                    return MalType::func_tco(
+                        arities,
+                        eval_env.clone(),
+                        Rc::new(Box::new(new_func)),
+                        false,
+                    );
                 } else {
                     //fist element in list is a symbol but not a special form
                     //return eval_list(&ast, &mut eval_env);
@@ -363,21 +1319,24 @@ pub fn eval(t1: &MalType, env: &mut Environment) -> MalType {
                     if eval_list_ast.is_list() {
                         let eval_list = eval_list_ast.get_list();
                         let first = &eval_list[0];
-                        if first.is_error() {
-                            return first.clone();
+                        //an error in the function or any argument, like the depth limit, is passed up
+                        if let Some(e) = eval_list.iter().find(|v| v.is_error()) {
+                            return e.clone();
                         } else if first.is_func() {
                             let (f, _is_macro) = first.get_func();
                             //println!("#1 in MalType::Func(f) = first: {:?}", f);
                             return f(eval_list[1..].to_vec());
                         } else if first.is_func_tco() {
-                            let (args, body, env, _func, _is_macro) = first.get_func_tco();
-                            ast = *body;
-                            let mut new_func_env = env.get_inner();
-
+                            let (arities, env, _func, _is_macro) = first.get_func_tco();
                             //bind function arguments
-                            new_func_env.bind_exprs(&args, &eval_list[1..]);
-
-                            eval_env = new_func_env;
+                            match bind_arity(&arities, &env, &eval_list[1..]) {
+                                Ok((body, new_func_env)) => {
+                                    ast = body;
+                                    eval_env = new_func_env;
+                                    recur_target = None;
+                                }
+                                Err(e) => return e,
+                            }
                         } else {
                             return MalType::error(format!("{} not found.", pr_str(first, true)));
                         }
@@ -393,21 +1352,24 @@ pub fn eval(t1: &MalType, env: &mut Environment) -> MalType {
                 if eval_list_ast.is_list() {
                     let eval_list = eval_list_ast.get_list();
                     let first = &eval_list[0];
-                    if first.is_error() {
-                        return first.clone();
+                    //an error in the function or any argument, like the depth limit, is passed up
+                    if let Some(e) = eval_list.iter().find(|v| v.is_error()) {
+                        return e.clone();
                     } else if first.is_func() {
                         let (f, _is_macro) = first.get_func();
                         //println!("#2 in MalType::Func(f) = first: {:?}", f);
                         return f(eval_list[1..].to_vec());
                     } else if first.is_func_tco() {
-                        let (args, body, env, _func, _is_macro) = first.get_func_tco();
-                        ast = *body;
-                        let mut new_func_env = env.get_inner();
-
+                        let (arities, env, _func, _is_macro) = first.get_func_tco();
                         //bind function arguments
-                        new_func_env.bind_exprs(&args, &eval_list[1..]);
-
-                        eval_env = new_func_env;
+                        match bind_arity(&arities, &env, &eval_list[1..]) {
+                            Ok((body, new_func_env)) => {
+                                ast = body;
+                                eval_env = new_func_env;
+                                recur_target = None;
+                            }
+                            Err(e) => return e,
+                        }
                     } else {
                         return MalType::error(format!("{} not found.", pr_str(first, true)));
                     }
@@ -453,7 +1415,8 @@ pub fn eval_ast(t: &MalType, env: &mut Environment) -> MalType {
                 } else {
                     tup.1.clone()
                 }
-            }).collect();
+            })
+            .collect();
         MalType::map(new_l)
     } else {
         t.clone()
@@ -468,6 +1431,7 @@ mod tests {
     use super::*;
     use core::init_environment;
     use reader::read_str;
+    use std::thread;
 
     #[test]
     fn evironmental_test() {
@@ -605,6 +1569,10 @@ mod tests {
         tests.push(("a", MalType::int(4)));
         tests.push(("(let* [z 9] z)", MalType::int(9)));
         tests.push(("(let* [p (+ 2 3) q (+ 2 p)] (+ p q))", MalType::int(12)));
+        //;; malformed bindings are errors, not panics
+        tests.push(("(let* (a) a)", MalType::error("let* requires an even number of binding forms".to_string())));
+        tests.push(("(let* 5 a)", MalType::error("let* bindings must be a list or vector".to_string())));
+        tests.push(("(let* (1 2) 3)", MalType::error("unsupported binding form: 1".to_string())));
 
         let mut v1 = Vec::new();
         v1.push(MalType::int(3));
@@ -793,7 +1761,7 @@ mod tests {
 
         //;; Testing pr-str
         tests.push(("(pr-str)", MalType::string("".to_string())));
-        tests.push(("(pr-str \"\")", MalType::string("".to_string())));
+        tests.push(("(pr-str \"\")", MalType::string("\"\"".to_string())));
         tests.push(("(pr-str \"abc\")", MalType::string("\"abc\"".to_string())));
         tests.push((
             "(pr-str \"abc def\" \"ghi jkl\")",
@@ -925,7 +1893,10 @@ mod tests {
         tests.push(("( (fn* (& more) (count more)) 1)", MalType::int(1)));
         tests.push(("( (fn* (& more) (count more)) )", MalType::int(0)));
         tests.push(("( (fn* (& more) (list? more)) )", MalType::bool(true)));
-        tests.push(("( (fn* (a & more) (list? more)) )", MalType::bool(true)));
+        tests.push((
+            "( (fn* (a & more) (list? more)) )",
+            MalType::error("wrong number of arguments (0), expected 1+".to_string()),
+        ));
         tests.push(("( (fn* (a & more) (count more)) 1 2 3)", MalType::int(2)));
         tests.push(("( (fn* (a & more) (count more)) 1)", MalType::int(0)));
         tests.push(("( (fn* (a & more) (list? more)) 1)", MalType::bool(true)));
@@ -1189,15 +2160,19 @@ mod tests {
         let mut v1 = Vec::new();
         v1.push(MalType::nil());
         tests.push(("(quasiquote (nil))", MalType::list(v1)));
+        let mut v1 = Vec::new();
+        v1.push(MalType::symbol("f".to_string()));
+        tests.push(("(quasiquote (f))", MalType::list(v1)));
 
         //;; Testing unquote
         tests.push(("(quasiquote (unquote 7))", MalType::int(7)));
+        //;; names defined in a namespace are qualified with it
         tests.push(("(def! a 8)", MalType::int(8)));
-        tests.push(("(quasiquote a)", MalType::symbol("a".to_string())));
+        tests.push(("(quasiquote a)", MalType::symbol("user/a".to_string())));
         tests.push(("(quasiquote (unquote a))", MalType::int(8)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("a".to_string()));
+        v1.push(MalType::symbol("user/a".to_string()));
         v1.push(MalType::int(3));
         tests.push(("(quasiquote (1 a 3))", MalType::list(v1)));
         let mut v1 = Vec::new();
@@ -1212,7 +2187,7 @@ mod tests {
         tests.push(("(def! b (quote (1 \"b\" \"d\")))", MalType::list(v1)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("b".to_string()));
+        v1.push(MalType::symbol("user/b".to_string()));
         v1.push(MalType::int(3));
         tests.push(("(quasiquote (1 b 3))", MalType::list(v1)));
         let mut v1 = Vec::new();
@@ -1237,7 +2212,7 @@ mod tests {
         tests.push(("(def! c (quote (1 \"b\" \"d\")))", MalType::list(v1)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("c".to_string()));
+        v1.push(MalType::symbol("user/c".to_string()));
         v1.push(MalType::int(3));
         tests.push(("(quasiquote (1 c 3))", MalType::list(v1)));
         let mut v1 = Vec::new();
@@ -1307,7 +2282,7 @@ mod tests {
         tests.push(("(def! b '(1 \"b\" \"d\"))", MalType::list(v1)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("b".to_string()));
+        v1.push(MalType::symbol("user/b".to_string()));
         v1.push(MalType::int(3));
         tests.push(("`(1 b 3)", MalType::list(v1)));
         let mut v1 = Vec::new();
@@ -1328,7 +2303,7 @@ mod tests {
         tests.push(("(def! c '(1 \"b\" \"d\"))", MalType::list(v1)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("c".to_string()));
+        v1.push(MalType::symbol("user/c".to_string()));
         v1.push(MalType::int(3));
         tests.push(("`(1 c 3)", MalType::list(v1)));
         let mut v1 = Vec::new();
@@ -1365,9 +2340,9 @@ mod tests {
         tests.push(("(def! a 8)", MalType::int(8)));
         let mut v1 = Vec::new();
         v1.push(MalType::int(1));
-        v1.push(MalType::symbol("a".to_string()));
+        v1.push(MalType::symbol("user/a".to_string()));
         v1.push(MalType::int(3));
-        tests.push(("`[1 a 3]", MalType::list(v1)));
+        tests.push(("`[1 a 3]", MalType::vector(v1)));
 
         //;; Testing splice-unquote with vectors
         let mut v1 = Vec::new();
@@ -1381,12 +2356,12 @@ mod tests {
         v1.push(MalType::string("b".to_string()));
         v1.push(MalType::string("d".to_string()));
         v1.push(MalType::int(3));
-        tests.push(("`[1 ~@c 3]", MalType::list(v1)));
+        tests.push(("`[1 ~@c 3]", MalType::vector(v1)));
 
         for tup in tests {
             //println!("{:?}", tup.0);
             let ast = read_str(tup.0);
-            assert_eq!(eval(&ast, &mut env), tup.1);
+            assert_eq!(eval(&ast, &mut env), tup.1, "{}", tup.0);
         }
     }
 
@@ -1422,7 +2397,7 @@ mod tests {
         let mut v1 = Vec::new();
         v1.push(MalType::symbol("if".to_string()));
         let mut v2 = Vec::new();
-        v2.push(MalType::symbol("not".to_string()));
+        v2.push(MalType::symbol("user/not".to_string()));
         v2.push(MalType::int(2));
         v1.push(MalType::list(v2));
         v1.push(MalType::int(3));
@@ -1531,4 +2506,826 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn eval_test_regex() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+
+        tests.push(("(regex? #\"a+\")", MalType::bool(true)));
+        tests.push(("(regex? \"a+\")", MalType::bool(false)));
+        tests.push(("(= #\"a+\" (re-pattern \"a+\"))", MalType::bool(true)));
+        tests.push(("(pr-str #\"a\\d\")", MalType::string("#\"a\\d\"".to_string())));
+        tests.push(("(re-find #\"\\d+\" \"abc 123 456\")", MalType::string("123".to_string())));
+        tests.push(("(re-find #\"\\d+\" \"abc\")", MalType::nil()));
+        tests.push(("(re-matches #\"\\d+\" \"123x\")", MalType::nil()));
+        tests.push(("(re-matches \"[0-9]+\" \"123\")", MalType::string("123".to_string())));
+
+        let v = vec![
+            MalType::string("a=1".to_string()),
+            MalType::string("a".to_string()),
+            MalType::string("1".to_string()),
+        ];
+        tests.push(("(re-find #\"(\\w)=(\\d)\" \"a=1\")", MalType::vector(v)));
+
+        let v = vec![
+            MalType::string("1".to_string()),
+            MalType::string("22".to_string()),
+        ];
+        tests.push(("(re-seq #\"\\d+\" \"1 a 22\")", MalType::list(v)));
+
+        let v = vec![
+            MalType::keyword(":key".to_string()),
+            MalType::string("a".to_string()),
+            MalType::keyword(":val".to_string()),
+            MalType::string("1".to_string()),
+        ];
+        tests.push(("(re-groups #\"(?P<key>\\w)=(?P<val>\\d)\" \"a=1\")", MalType::map(v)));
+
+        tests.push(("(replace \"a-b-c\" \"-\" \"+\")", MalType::string("a+b+c".to_string())));
+        tests.push(("(replace \"a1b22\" #\"\\d+\" \"<$0>\")", MalType::string("a<1>b<22>".to_string())));
+        tests.push(("(replace \"a1b2\" #\"\\d\" (fn* (m) (str \"<\" m \">\")))", MalType::string("a<1>b<2>".to_string())));
+
+        let v = vec![
+            MalType::string("a".to_string()),
+            MalType::string("b".to_string()),
+            MalType::string("c".to_string()),
+        ];
+        tests.push(("(split \"a, b,c\" #\",\\s*\")", MalType::list(v)));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+
+        //an invalid pattern's error reaches the caller instead of being passed to regex?
+        let ast = read_str("(regex? (re-pattern \"(\"))");
+        assert!(eval(&ast, &mut env).is_error());
+    }
+
+    #[test]
+    fn eval_test_char() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+
+        tests.push(("\\a", MalType::char('a')));
+        tests.push(("(char? \\a)", MalType::bool(true)));
+        tests.push(("(char? \"a\")", MalType::bool(false)));
+        tests.push(("(char 97)", MalType::char('a')));
+        tests.push(("(char \"é\")", MalType::char('é')));
+        tests.push(("(int \\a)", MalType::int(97)));
+        tests.push(("(int 2.5)", MalType::int(2)));
+        tests.push(("(= \\a (char 97))", MalType::bool(true)));
+        tests.push(("(pr-str \\a \\newline \\space)", MalType::string("\\a \\newline \\space".to_string())));
+        tests.push(("(str \\a \\space \\b)", MalType::string("a b".to_string())));
+        tests.push(("(seq \"\")", MalType::nil()));
+        tests.push(("(seq [])", MalType::nil()));
+
+        let v = vec![MalType::char('a'), MalType::char('b')];
+        tests.push(("(seq \"ab\")", MalType::list(v)));
+        let v = vec![MalType::int(1), MalType::int(2)];
+        tests.push(("(seq [1 2])", MalType::list(v)));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+    }
+
+    #[test]
+    fn eval_test_fs() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let dir = ::std::env::temp_dir().join(format!("mal_fs_test_{}", ::std::process::id()));
+        let dir = dir.to_string_lossy().into_owned();
+        let file = format!("{}/a.txt", dir);
+        let moved = format!("{}/b.txt", dir);
+
+        let mut tests: Vec<(String, MalType)> = Vec::new();
+
+        tests.push((format!("(mkdir \"{}\")", dir), MalType::nil()));
+        tests.push((format!("(file-exists? \"{}\")", file), MalType::bool(false)));
+        tests.push((format!("(spit \"{}\" \"one\\n\")", file), MalType::nil()));
+        tests.push((format!("(spit \"{}\" 2 :append true)", file), MalType::nil()));
+        tests.push((format!("(slurp \"{}\")", file), MalType::string("one\n2".to_string())));
+        tests.push((format!("(file-exists? \"{}\")", file), MalType::bool(true)));
+
+        let v = vec![MalType::string("one".to_string()), MalType::string("2".to_string())];
+        tests.push((format!("(read-lines \"{}\")", file), MalType::list(v)));
+
+        tests.push((format!("(rename-file \"{}\" \"{}\")", file, moved), MalType::nil()));
+        let v = vec![MalType::string("b.txt".to_string())];
+        tests.push((format!("(list-dir \"{}\")", dir), MalType::list(v)));
+        tests.push((format!("(delete-file \"{}\")", moved), MalType::nil()));
+        tests.push((format!("(delete-file \"{}\")", dir), MalType::nil()));
+        tests.push((format!("(file-exists? \"{}\")", dir), MalType::bool(false)));
+
+        for tup in tests {
+            let ast = read_str(&tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+
+        let info = eval(&read_str(&format!("(file-info \"{}\")", ::std::env::temp_dir().to_string_lossy())), &mut env);
+        let info = info.get_list();
+        assert_eq!(info[4], MalType::keyword(":dir?".to_string()));
+        assert_eq!(info[5], MalType::bool(true));
+
+        let missing = eval(&read_str(&format!("(slurp \"{}\")", file)), &mut env);
+        assert!(missing.is_error());
+        assert!(missing.get_string().starts_with(&format!("slurp: {}: ", file)));
+    }
+
+    #[test]
+    fn eval_test_handles() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let file = ::std::env::temp_dir().join(format!("mal_handle_test_{}.txt", ::std::process::id()));
+        let file = file.to_string_lossy().into_owned();
+
+        let mut tests: Vec<(String, MalType, bool)> = Vec::new();
+
+        tests.push(("(handle? *out*)".to_string(), MalType::bool(true), true));
+        tests.push((format!("(def! h (open \"{}\" :write))", file), MalType::nil(), false));
+        tests.push(("(write h \"é1\" \\newline 2 \"\\n\")".to_string(), MalType::nil(), true));
+        tests.push(("(close h)".to_string(), MalType::nil(), true));
+        tests.push(("(close h)".to_string(), MalType::nil(), true));
+        tests.push((
+            "(write h 3)".to_string(),
+            MalType::error(format!("write: {} is not open for writing", file)),
+            true,
+        ));
+        tests.push((
+            format!("(with-open [h (open \"{}\" :append)] (write h \"last\") 7)", file),
+            MalType::int(7),
+            true,
+        ));
+        let v = vec![
+            MalType::char('é'),
+            MalType::string("1".to_string()),
+            MalType::string("2".to_string()),
+            MalType::string("last".to_string()),
+            MalType::nil(),
+        ];
+        tests.push((
+            format!("(with-open [r (open \"{}\")] (list (read-char r) (read-line r) (read-line r) (read-line r) (read-line r)))", file),
+            MalType::list(v),
+            true,
+        ));
+        tests.push((
+            "(def! count-lines (fn* (r n) (if (= nil (read-line r)) n (count-lines r (+ n 1)))))".to_string(),
+            MalType::nil(),
+            false,
+        ));
+        tests.push((
+            format!("(with-open [r (open \"{}\")] (count-lines r 0))", file),
+            MalType::int(3),
+            true,
+        ));
+
+        //;; the handle is closed, and its writes kept, even when the body errors
+        tests.push(("(def! hh (atom nil))".to_string(), MalType::nil(), false));
+        tests.push((
+            format!("(with-open [h (open \"{}\" :write)] (reset! hh h) (write h \"x\") (nth [] 5))", file),
+            MalType::error("nth: index is greater than length of list".to_string()),
+            true,
+        ));
+        tests.push((
+            "(write @hh \"y\")".to_string(),
+            MalType::error(format!("write: {} is not open for writing", file)),
+            true,
+        ));
+        tests.push((format!("(slurp \"{}\")", file), MalType::string("x".to_string()), true));
+        tests.push((
+            "(with-open [h 1] 2)".to_string(),
+            MalType::error("with-open: 1 is not a handle".to_string()),
+            true,
+        ));
+        tests.push((
+            "(with-open [h] 2)".to_string(),
+            MalType::error("with-open requires a vector of name and handle pairs".to_string()),
+            true,
+        ));
+
+        for tup in tests {
+            let ast = read_str(&tup.0);
+            let result = eval(&ast, &mut env);
+            if tup.2 {
+                assert_eq!(result, tup.1, "{}", tup.0);
+            }
+        }
+
+        ::std::fs::remove_file(&file).unwrap();
+        let missing = eval(&read_str(&format!("(with-open [r (open \"{}\")] (read-line r))", file)), &mut env);
+        assert!(missing.get_string().starts_with("open: "));
+    }
+
+    #[test]
+    fn eval_test_sh() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+
+        let v = vec![
+            MalType::keyword(":exit".to_string()),
+            MalType::int(3),
+            MalType::keyword(":out".to_string()),
+            MalType::string("out\n".to_string()),
+            MalType::keyword(":err".to_string()),
+            MalType::string("err\n".to_string()),
+        ];
+        tests.push(("(sh \"sh\" \"-c\" \"echo out; echo err >&2; exit 3\")", MalType::map(v)));
+        tests.push(("(let* ({out :out} (sh \"cat\" :in \"piped\")) out)", MalType::string("piped".to_string())));
+        tests.push(("(let* ({out :out} (sh \"pwd\" :dir \"/\")) out)", MalType::string("/\n".to_string())));
+        tests.push((
+            "(let* ({out :out} (sh \"sh\" \"-c\" \"echo $MAL_SH_TEST\" :env {\"MAL_SH_TEST\" 42})) out)",
+            MalType::string("42\n".to_string()),
+        ));
+        tests.push((
+            "(sh \"sleep\" \"5\" :timeout 50)",
+            MalType::error("sh: sleep timed out after 50 ms".to_string()),
+        ));
+        tests.push((
+            "(sh \"env\" :env {:KEY 1})",
+            MalType::error("sh: :env keys must be strings, not :KEY".to_string()),
+        ));
+
+        eval(&read_str("(def! p (sh-stream \"cat\"))"), &mut env);
+        tests.push(("(let* ({in :in} p) (write in \"line one\\n\"))", MalType::nil()));
+        tests.push(("(let* ({in :in} p) (close in))", MalType::nil()));
+        tests.push(("(let* ({out :out} p) (read-line out))", MalType::string("line one".to_string())));
+        tests.push(("(let* ({out :out} p) (read-line out))", MalType::nil()));
+        tests.push(("(let* ({wait :wait} p) (wait))", MalType::int(0)));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+
+        assert!(eval(&read_str("(sh \"no-such-command-for-mal\")"), &mut env).is_error());
+
+        //;; a grandchild holding the pipes open doesn't hold up the timeout
+        let start = ::std::time::Instant::now();
+        let slow = eval(&read_str("(sh \"sh\" \"-c\" \"sleep 3; echo hi\" :timeout 100)"), &mut env);
+        assert!(slow.is_error());
+        assert!(start.elapsed() < ::std::time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn eval_test_system() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+
+        tests.push(("(setenv \"MAL_SYSTEM_TEST\" 12)", MalType::nil()));
+        tests.push(("(getenv \"MAL_SYSTEM_TEST\")", MalType::string("12".to_string())));
+        tests.push(("(let* ({v \"MAL_SYSTEM_TEST\"} (env-map)) v)", MalType::string("12".to_string())));
+        tests.push(("(setenv \"MAL_SYSTEM_TEST\" nil)", MalType::nil()));
+        tests.push(("(getenv \"MAL_SYSTEM_TEST\")", MalType::nil()));
+        tests.push(("(getenv \"MAL_SYSTEM_TEST\" \"none\")", MalType::string("none".to_string())));
+        tests.push(("(pid)", MalType::int(i64::from(::std::process::id()))));
+        tests.push(("(= \"\" (hostname))", MalType::bool(false)));
+
+        tests.push(("*file*", MalType::nil()));
+        tests.push(("(load-file \"mal_tests/incD.mal\")", MalType::string("mal_tests/incD.mal".to_string())));
+        tests.push(("loaded-from", MalType::string("mal_tests/incD.mal".to_string())));
+        tests.push(("*file*", MalType::nil()));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+
+        assert_eq!(
+            eval(&read_str("(cwd)"), &mut env),
+            MalType::string(::std::env::current_dir().unwrap().to_string_lossy().into_owned())
+        );
+
+        assert_eq!(core::exit_status(), None);
+        assert_eq!(eval(&read_str("(do (exit 3) (def! after-exit 1))"), &mut env), MalType::nil());
+        assert_eq!(core::exit_status(), Some(3));
+        assert!(env.find("after-exit").is_none());
+    }
+
+    #[test]
+    fn eval_test_source() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        assert_eq!(
+            core::eval_source("#!/usr/bin/env mal\n(def! s1 1)\n(+ s1 1)", &mut env),
+            MalType::int(2)
+        );
+        assert_eq!(
+            core::eval_source("(def! s2 1) (undefined-fn) (def! s3 1)", &mut env),
+            MalType::error("undefined-fn not found.".to_string())
+        );
+        assert_eq!(env.get("s2"), MalType::int(1));
+        assert!(env.find("s3").is_none());
+        assert_eq!(core::eval_source("", &mut env), MalType::nil());
+    }
+
+    #[test]
+    fn eval_test_print_limits() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+        let s = |x: &str| MalType::string(x.to_string());
+
+        tests.push(("(def! a (atom 1))", MalType::atom(MalType::int(1))));
+        tests.push(("(do (reset! a a) (pr-str a))", s("(atom (atom ...))")));
+        tests.push(("(do (reset! a [1 a]) (pr-str a))", s("(atom [1 (atom ...)])")));
+        tests.push(("(pr-str [a a])", s("[(atom [1 (atom ...)]) (atom [1 (atom ...)])]")));
+        tests.push(("(def! *print-length* 2)", MalType::int(2)));
+        tests.push(("(pr-str '(1 2 3) [1 2] {:a 1 :b 2 :c 3})", s("(1 2 ...) [1 2] {:a 1 :b 2 ...}")));
+        tests.push(("(str [\"a\" \"b\" \"c\"])", s("[a b c]")));
+        tests.push(("(str (atom [1 [2 [3]]]))", s("(atom [1 [2 [3]]])")));
+        tests.push(("(def! *print-length* 0)", MalType::int(0)));
+        tests.push(("(pr-str [1] [])", s("[...] []")));
+        tests.push(("(def! *print-length* nil)", MalType::nil()));
+        tests.push(("(def! *print-level* 2)", MalType::int(2)));
+        tests.push(("(pr-str [1 [2 [3 [4]]]] 5)", s("[1 [2 ...]] 5")));
+        tests.push(("(pr-str (atom [1 [2 [3]]]))", s("(atom [1 [2 ...]])")));
+        tests.push(("(def! *print-level* 0)", MalType::int(0)));
+        tests.push(("(pr-str {:a 1} :b)", s("... :b")));
+        tests.push(("(def! *print-level* nil)", MalType::nil()));
+        tests.push(("(pr-str [1 [2 [3 [4]]]])", s("[1 [2 [3 [4]]]]")));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1);
+        }
+    }
+
+    #[test]
+    fn eval_test_destructuring() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+        let ints = |v: &[i64]| MalType::list(v.iter().map(|i| MalType::int(*i)).collect());
+        let err = |e: &str| MalType::error(e.to_string());
+
+        //;; sequential
+        tests.push(("(let* ([a b] [1 2]) (list a b))", ints(&[1, 2])));
+        tests.push(("(let* ([a b c] '(1 2)) (list a b c))", MalType::list(vec![MalType::int(1), MalType::int(2), MalType::nil()])));
+        tests.push(("(let* ([a & r] [1 2 3]) r)", ints(&[2, 3])));
+        tests.push(("(let* ([a & r] [1]) r)", ints(&[])));
+        tests.push(("(let* ([a [b c] :as all] [1 [2 3] 4]) (list a b c (count all)))", ints(&[1, 2, 3, 3])));
+        tests.push(("(let* ([a b] nil) (list a b))", MalType::list(vec![MalType::nil(), MalType::nil()])));
+        tests.push(("(let* ([_ & [b]] [1 2 3]) b)", MalType::int(2)));
+
+        //;; associative
+        tests.push(("(let* ({:keys [x y]} {:x 1 :y 2}) (list x y))", ints(&[1, 2])));
+        tests.push(("(let* ({:keys [x y] :or {y 5}} {:x 1}) (list x y))", ints(&[1, 5])));
+        tests.push(("(let* ({:keys [x] :or {x (+ 2 3)}} nil) x)", MalType::int(5)));
+        tests.push(("(let* ({:strs [s]} {\"s\" 7}) s)", MalType::int(7)));
+        tests.push(("(let* ({a :a [b c] :v :as m} {:a 1 :v [2 3]}) (list a b c (if (= m {:a 1 :v [2 3]}) 1 0)))", ints(&[1, 2, 3, 1])));
+        tests.push(("(let* ([{:keys [n]} & more] [{:n 9} 1]) (cons n more))", ints(&[9, 1])));
+
+        //;; function parameters
+        tests.push(("((fn* ([a b] {:keys [c]}) (list a b c)) [1 2] {:c 3})", ints(&[1, 2, 3])));
+        tests.push(("((fn* (a & {:keys [k]}) (list a k)) 1 :k 2)", ints(&[1, 2])));
+        tests.push(("((fn* (a & {:keys [k]}) (list a k)) 1 {:k 2})", ints(&[1, 2])));
+        tests.push(("((fn* (a & [{:keys [k]}]) (list a k)) 1 {:k 2})", ints(&[1, 2])));
+        tests.push(("((fn* (& xs :as args) (= xs args)) 1 2)", MalType::bool(true)));
+
+        //;; shapes that don't fit
+        tests.push(("(let* ([a] 5) a)", err("can't destructure 5 as a sequence")));
+        tests.push(("(let* ({:keys [a]} [1]) a)", err("can't destructure [1] as a map")));
+        tests.push(("(let* ({:keys [a]} 1) a)", err("can't destructure 1 as a map")));
+        tests.push(("(let* ({:keys [a]} [1 2]) a)", err("can't destructure [1 2] as a map")));
+        tests.push(("(let* ({:keys [a]} '(:a 1)) a)", err("can't destructure (:a 1) as a map")));
+        tests.push(("((fn* (& {:keys [k]}) k) :k)", err("keyword arguments need a value for each key: (:k)")));
+        tests.push(("((fn* (& {:keys [k]}) k))", MalType::nil()));
+        tests.push(("(let* (1 2) 1)", err("unsupported binding form: 1")));
+        tests.push(("(let* ([a &] [1]) a)", err("& must be followed by a binding")));
+        tests.push(("(let* ([& r b] [1]) b)", err("only :as can follow & rest in [& r b]")));
+        tests.push(("(let* ({:keys [a] :or 1} {}) a)", err(":or takes a map of defaults")));
+        tests.push(("((fn* ([a]) a) 1)", err("can't destructure 1 as a sequence")));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_multi_arity() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let defs = [
+            "(def! f (fn* ([] 0) ([x] 1) ([x y] 2) ([x y & more] (+ 2 (count more)))))",
+            "(def! sum (fn* ([n] (sum n 0)) ([n acc] (if (= n 0) acc (sum (- n 1) (+ n acc))))))",
+        ];
+        for def in defs.iter() {
+            eval(&read_str(def), &mut env);
+        }
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+        let err = |e: &str| MalType::error(e.to_string());
+
+        tests.push(("(f)", MalType::int(0)));
+        tests.push(("(f :a)", MalType::int(1)));
+        tests.push(("(f :a :b)", MalType::int(2)));
+        tests.push(("(f :a :b :c :d)", MalType::int(4)));
+
+        //;; arities calling each other, and tail calls through dispatch
+        tests.push(("(sum 10000)", MalType::int(50005000)));
+
+        //;; implicit do
+        tests.push(("((fn* (a) (def! b (+ a 1)) (* b 2)) 4)", MalType::int(10)));
+        tests.push(("((fn* ([a] (def! b (+ a 1)) (* b 2))) 3)", MalType::int(8)));
+        tests.push(("((fn* ()))", MalType::nil()));
+
+        //;; arity errors
+        tests.push(("((fn* (a b) a) 1)", err("wrong number of arguments (1), expected 2")));
+        tests.push(("((fn* (a b) a) 1 2 3)", err("wrong number of arguments (3), expected 2")));
+        tests.push(("((fn* ([a] 1) ([a b c & d] 2)) 1 2)", err("wrong number of arguments (2), expected 1 or 3+")));
+        tests.push(("(fn* ([a] 1) ([b] 2))", err("fn* can't have two arities taking 1 arguments")));
+        tests.push(("(fn* ([& a] 1) ([b & c] 2))", err("fn* can't have more than one variadic arity")));
+        tests.push(("(fn* x 1)", err("bind list is not a list: x ")));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_loop_recur() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+        eval(&read_str("(def! expansions (atom 0))"), &mut env);
+        eval(&read_str("(defmacro! counted (fn* (x) (do (swap! expansions + 1) x)))"), &mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+        let err = |e: &str| MalType::error(e.to_string());
+
+        tests.push(("(loop* [i 0 acc 0] (if (= i 100000) acc (recur (+ i 1) (+ acc i))))", MalType::int(4999950000)));
+        tests.push(("(loop* [i 0] (cond (< i 5) (recur (+ i 1)) :else i))", MalType::int(5)));
+        tests.push(("(loop* [i 0] (let* (j (+ i 1)) (if (< j 3) (recur j) j)))", MalType::int(3)));
+        tests.push(("(loop* [[a & r] [1 2 3] acc 0] (if a (recur r (+ acc a)) acc))", MalType::int(6)));
+        tests.push(("(loop* [i 0] (if (< i 2) (do (+ 1 2) (recur (+ i 1))) (loop* [j i] (if (< j 4) (recur (+ j 1)) j))))", MalType::int(4)));
+        tests.push(("(loop* [i 0])", MalType::nil()));
+
+        //;; recur in a function called through a builtin still runs in constant stack
+        tests.push(("(def! a (atom 0))", MalType::atom(MalType::int(0))));
+        tests.push(("(swap! a (fn* (n) (loop* [i n] (if (< i 50000) (recur (+ i 1)) i))))", MalType::int(50000)));
+
+        //;; misplaced recur
+        tests.push(("(loop* [i 0] (+ 1 (recur i)))", err("recur must be in tail position: (recur i)")));
+        tests.push(("(loop* [i 0] (fn* (x) (recur x)))", err("recur must be in tail position: (recur x)")));
+        tests.push(("(loop* [i (recur 1)] i)", err("recur must be in tail position: (recur 1)")));
+        tests.push(("(recur 1)", err("recur must be in tail position of loop*")));
+        tests.push(("(loop* [i 0] (if (< i 3) (recur) i))", err("recur expects 1 arguments, got 0")));
+        tests.push(("(loop* 5 1)", err("loop* bindings must be a list or vector")));
+        tests.push(("(loop* [i 0] (let* x 1))", err("let* bindings must be a list or vector")));
+
+        //;; the body is macroexpanded once, for checking and running alike
+        tests.push(("(loop* [i 0] (if (< i 3) (recur (+ i 1)) (counted i)))", MalType::int(3)));
+        tests.push(("@expansions", MalType::int(1)));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(eval(&ast, &mut env), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_depth_limit() {
+        //like main, give the interpreter a stack big enough for the default limit
+        let test = || {
+            let mut env = Environment::new();
+            init_environment(&mut env);
+
+            let mut tests: Vec<(&str, MalType)> = Vec::new();
+            let exceeded = |n: i64| MalType::error(format!("stack depth exceeded ({})", n));
+            tests.push(("(def! f (fn* (n) (if (= n 0) 0 (+ 1 (f (- n 1))))))", MalType::nil()));
+            tests.push(("(f 1000)", MalType::int(1000)));
+            tests.push(("(f 100000)", exceeded(DEFAULT_MAX_DEPTH as i64)));
+            tests.push(("(list 1 (f 100000))", exceeded(DEFAULT_MAX_DEPTH as i64)));
+
+            //;; tail calls and loops don't nest
+            tests.push(("(def! g (fn* (n) (if (= n 0) :done (g (- n 1)))))", MalType::nil()));
+            tests.push(("(g 100000)", MalType::keyword(":done".to_string())));
+
+            //;; the limit is read again for each top level form
+            tests.push(("(def! *max-depth* 100)", MalType::int(100)));
+            tests.push(("(f 10)", MalType::int(10)));
+            tests.push(("(f 1000)", exceeded(100)));
+
+            //;; but never past what the stack has room for
+            tests.push(("(def! *max-depth* 10000000)", MalType::int(10000000)));
+            tests.push(("(f 100000)", exceeded(MAX_DEPTH_CEILING as i64)));
+
+            for tup in tests {
+                let ast = read_str(tup.0);
+                let result = eval(&ast, &mut env);
+                if !tup.1.is_nil() {
+                    assert_eq!(result, tup.1, "{}", tup.0);
+                }
+            }
+        };
+
+        let handle = thread::Builder::new().stack_size(STACK_SIZE).spawn(test);
+        handle.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn eval_test_gensym() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, MalType)> = Vec::new();
+        let bools = |v: &[bool]| MalType::list(v.iter().map(|b| MalType::bool(*b)).collect());
+
+        tests.push(("(= (gensym) (gensym))", MalType::bool(false)));
+        tests.push(("(= (gensym \"x\") (gensym \"x\"))", MalType::bool(false)));
+        tests.push(("(gensym 1)", MalType::error("gensym takes an optional prefix string".to_string())));
+
+        //;; the same foo# within one quasiquote, a fresh one in the next
+        tests.push(("(let* (q `(a# a# b#)) (list (= (nth q 0) (nth q 1)) (= (nth q 0) (nth q 2))))", bools(&[true, false])));
+        tests.push(("(= `a# `a#)", MalType::bool(false)));
+        tests.push(("`(# a#b)", MalType::list(vec![MalType::symbol("#".to_string()), MalType::symbol("a#b".to_string())])));
+
+        //;; macros using them don't capture the caller's names
+        tests.push(("(let* (or_FIXME 5) (or nil or_FIXME))", MalType::int(5)));
+        tests.push(("(defmacro! swap-pair (fn* (a b) `(let* (t# ~a) (list ~b t#))))", MalType::nil()));
+        tests.push(("(let* (t 1) (swap-pair t 2))", MalType::list(vec![MalType::int(2), MalType::int(1)])));
+
+        //;; defined names are qualified with their namespace, so a namespace
+        //;; redefining them can't capture a macro's use of them
+        let kw = |k: &str| MalType::keyword(k.to_string());
+        tests.push(("`(not x)", MalType::list(vec![MalType::symbol("user/not".to_string()), MalType::symbol("x".to_string())])));
+        tests.push(("(defmacro! unless (fn* (c x) `(if (not ~c) ~x :no)))", MalType::nil()));
+        tests.push(("(def! counter 0)", MalType::int(0)));
+        tests.push(("(defmacro! reset-counter (fn* () `(def! counter 10)))", MalType::nil()));
+        tests.push(("(reset-counter)", MalType::int(10)));
+        tests.push(("(in-ns 'other)", MalType::symbol("other".to_string())));
+        tests.push(("(def! not (fn* (x) :captured))", MalType::nil()));
+        tests.push(("(user/unless false :yes)", kw(":yes")));
+        tests.push(("(user/reset-counter)", MalType::error("can't define user/counter in namespace other".to_string())));
+        tests.push(("(in-ns 'user)", MalType::symbol("user".to_string())));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            let result = eval(&ast, &mut env);
+            if !tup.1.is_nil() {
+                assert_eq!(result, tup.1, "{}", tup.0);
+            }
+        }
+    }
+
+    #[test]
+    fn eval_test_macroexpand() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, &str)> = Vec::new();
+        tests.push(("(macroexpand-1 (cond false 1 true 2))", "(if false 1 (cond true 2))"));
+        tests.push(("(macroexpand-1 (+ 1 2))", "(+ 1 2)"));
+        tests.push(("(macroexpand-1 (cond (cond a 1) 2))", "(if (cond a 1) 2 (cond))"));
+        tests.push(("(macroexpand (cond (cond a 1) 2))", "(if (cond a 1) 2 (cond))"));
+        tests.push(("(defmacro! when (fn* (c & body) `(cond ~c (do ~@body))))", "#<macro>"));
+        tests.push(("(macroexpand-1 (when a 1))", "(user/cond a (do 1))"));
+        tests.push(("(macroexpand (when a 1))", "(if a (do 1) (cond))"));
+
+        //;; every subform, but not quoted data, names or parameter lists
+        tests.push(("(macroexpand-all (cond (cond a 1) 2))", "(if (if a 1 nil) 2 nil)"));
+        tests.push(("(macroexpand-all [(cond a 1) {:k (cond b 2)}])", "[(if a 1 nil) {:k (if b 2 nil)}]"));
+        tests.push(("(macroexpand-all (f '(cond a 1)))", "(f (quote (cond a 1)))"));
+        tests.push(("(macroexpand-all (let* [cond (cond a 1)] cond))", "(let* [cond (if a 1 nil)] cond)"));
+        tests.push(("(macroexpand-all (fn* (cond) (cond a 1)))", "(fn* (cond) (if a 1 nil))"));
+        tests.push(("(macroexpand-all (fn* ([cond] 1) ([a b] (cond a b))))", "(fn* ([cond] 1) ([a b] (if a b nil)))"));
+        tests.push(("(macroexpand-all (def! x (cond a 1)))", "(def! x (if a 1 nil))"));
+        tests.push(("(macroexpand-all `(cond ~(cond a 1) [~@(cond b 2)]))", "(quasiquote (cond (unquote (if a 1 nil)) [(splice-unquote (if b 2 nil))]))"));
+        tests.push(("(macroexpand-all `{:a ~(cond x 1)})", "(quasiquote {:a (unquote (if x 1 nil))})"));
+        tests.push(("(macroexpand-1)", "macroexpand-1 expects 1 argument, got 0"));
+        tests.push(("(macroexpand-all a b)", "macroexpand-all expects 1 argument, got 2"));
+        tests.push(("(macroexpand)", "macroexpand expects 1 argument, got 0"));
+        tests.push(("(quasiquoteexpand)", "quasiquoteexpand expects 1 argument, got 0"));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), true), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_quasiquote_collections() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, &str)> = Vec::new();
+        tests.push(("(def! a 8)", "8"));
+        tests.push(("(def! c '(1 2))", "(1 2)"));
+
+        //;; vectors stay vectors and maps are traversed
+        tests.push(("`[1 ~a]", "[1 8]"));
+        tests.push(("`[1 ~@c 3]", "[1 1 2 3]"));
+        tests.push(("`[[~a] ~@[3 4]]", "[[8] 3 4]"));
+        tests.push(("`{:k ~a :l [~@c]}", "{:k 8 :l [1 2]}"));
+        tests.push(("`(1 [~@c] {:m ~(+ a 1)})", "(1 [1 2] {:m 9})"));
+        tests.push(("`[]", "[]"));
+        tests.push(("`{}", "{}"));
+
+        //;; the forms quasiquote builds
+        tests.push(("(quasiquoteexpand (1 ~a ~@c))", "(cons 1 (cons a (concat c ())))"));
+        tests.push(("(quasiquoteexpand [x])", "(vec (cons (quote x) ()))"));
+        tests.push(("(quasiquoteexpand {:k ~a})", "(hash-map :k a)"));
+        tests.push(("(quasiquoteexpand ~a)", "a"));
+        tests.push(("(quasiquoteexpand 1)", "1"));
+
+        tests.push(("(vec '(1 2))", "[1 2]"));
+        tests.push(("(vec nil)", "[]"));
+        tests.push(("(hash-map :a 1 :b 2 :a 3)", "{:a 3 :b 2}"));
+        tests.push(("(hash-map :a)", "hash-map takes an even number of arguments"));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), false), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_binding() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, &str)> = Vec::new();
+        tests.push(("(def-dynamic! *depth* 0)", "0"));
+        tests.push(("(def! show (fn* () *depth*))", "#<function>"));
+        tests.push(("(binding [*depth* 1] (show))", "1"));
+        tests.push(("(binding [*depth* 1] (list (show) (binding [*depth* 2] (show)) (show)))", "(1 2 1)"));
+        tests.push(("(show)", "0"));
+
+        //;; restored when the body errors, and settings can be rebound
+        tests.push(("(binding [*depth* 5] (nth [1] 9))", "nth: index is greater than length of list"));
+        tests.push(("*depth*", "0"));
+        tests.push(("(binding [*print-length* 2] (pr-str [1 2 3 4]))", "[1 2 ...]"));
+        tests.push(("(pr-str [1 2 3])", "[1 2 3]"));
+
+        //;; only dynamic vars can be rebound
+        tests.push(("(def! plain 1)", "1"));
+        tests.push(("(binding [plain 2] plain)", "can't dynamically bind non-dynamic var: plain"));
+        tests.push(("(binding [*depth*] 1)", "binding requires a vector of var and value pairs"));
+        tests.push(("((fn* () (def-dynamic! *inner* 3)))", "3"));
+        tests.push(("(binding [*inner* 4] *inner*)", "4"));
+
+        //;; dynamic vars belong to the namespace that defines them
+        tests.push(("(in-ns 'lib)", "lib"));
+        tests.push(("(def-dynamic! *level* 1)", "1"));
+        tests.push(("(def! level (fn* () *level*))", "#<function>"));
+        tests.push(("(binding [*level* 2] (level))", "2"));
+        tests.push(("(in-ns 'user)", "user"));
+        tests.push(("*level*", "*level* not found."));
+        tests.push(("(binding [*level* 3] 1)", "can't dynamically bind non-dynamic var: *level*"));
+        tests.push(("(binding [lib/*level* 3] (lib/level))", "3"));
+        tests.push(("(alias 'l 'lib)", "nil"));
+        tests.push(("(binding [l/*level* 4] (lib/level))", "4"));
+        tests.push(("(lib/level)", "1"));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), false), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_namespaces() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let mut tests: Vec<(&str, &str)> = Vec::new();
+        tests.push(("*ns*", "user"));
+        tests.push(("(ns my.lib)", "my.lib"));
+        tests.push(("*ns*", "my.lib"));
+        tests.push(("(def! not (fn* (x) :shadowed))", "#<function>"));
+        tests.push(("(def! helper (fn* (x) (* x 10)))", "#<function>"));
+        tests.push(("(def! api (fn* (x) (helper x)))", "#<function>"));
+        tests.push(("(defmacro! twice (fn* (x) `(helper (helper ~x))))", "#<macro>"));
+        tests.push(("(def! use-twice (fn* (x) (twice x)))", "#<function>"));
+        tests.push(("(def! show-twice (fn* () (macroexpand-1 (twice 1))))", "#<function>"));
+        tests.push(("(not true)", ":shadowed"));
+
+        //;; back in user, the library's definitions don't collide with ours
+        tests.push(("(in-ns 'user)", "user"));
+        tests.push(("(not true)", "false"));
+        tests.push(("helper", "helper not found."));
+        tests.push(("(my.lib/api 2)", "20"));
+        tests.push(("(my.lib/twice 1)", "100"));
+        tests.push(("(my.lib/use-twice 1)", "100"));
+        tests.push(("(my.lib/show-twice)", "(my.lib/helper (my.lib/helper 1))"));
+        tests.push(("(macroexpand (my.lib/twice 1))", "(my.lib/helper (my.lib/helper 1))"));
+        tests.push(("my.lib/missing", "my.lib/missing not found."));
+        tests.push(("`(helper x)", "(helper x)"));
+
+        //;; alias and refer
+        tests.push(("(alias 'l 'my.lib)", "nil"));
+        tests.push(("(l/api 3)", "30"));
+        tests.push(("(refer 'my.lib :only '[api])", "nil"));
+        tests.push(("(api 4)", "40"));
+        tests.push(("(refer 'my.lib :only '[nope])", "my.lib/nope not found."));
+        tests.push(("(alias 'x 'no.such)", "no namespace: no.such"));
+        tests.push(("(refer 'no.such)", "no namespace: no.such"));
+        tests.push(("(in-ns 'other)", "other"));
+        tests.push(("(l/api 3)", "l/api not found."));
+        tests.push(("(refer 'my.lib)", "nil"));
+        tests.push(("(helper 5)", "50"));
+        tests.push(("(in-ns \"user\")", "in-ns takes a namespace symbol"));
+
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), false), tup.1, "{}", tup.0);
+        }
+    }
+
+    #[test]
+    fn eval_test_require() {
+        let mut env = Environment::new();
+        init_environment(&mut env);
+
+        let dir = ::std::env::temp_dir().join(format!("mal_require_test_{}", ::std::process::id()));
+        let modules = vec![
+            ("util/text.mal", "(ns util.text) (def! loads (atom 0)) (swap! loads + 1) (def! shout (fn* (s) (str s \"!\")))"),
+            ("app.mal", "(ns app (:require [util.text :as t :refer [shout]])) (def! run (fn* () (t/shout (shout \"hi\"))))"),
+            ("foo/bar.mal", "(ns foo.bar) (defmacro! unless (fn* (c x) `(if ~c nil ~x))) (def! use-unless (fn* (c) (unless c :ran)))"),
+            ("a.mal", "(ns a (:require b))"),
+            ("b.mal", "(ns b (:require a))"),
+            ("nons.mal", "(def! x 1)"),
+            ("half.mal", "(ns half) (def! before 1) (nope)"),
+        ];
+        for (file, code) in modules {
+            let path = dir.join(file);
+            ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            ::std::fs::write(path, code).unwrap();
+        }
+        let dir = dir.to_string_lossy().into_owned();
+
+        let ast = read_str("(require 'util.text)");
+        assert_eq!(
+            pr_str(&eval(&ast, &mut env), false),
+            "require: can't find util/text.mal in *load-path*"
+        );
+        let ast = read_str(&format!("(def! *load-path* [\"{}\"])", dir));
+        assert_eq!(pr_str(&eval(&ast, &mut env), true), format!("[\"{}\"]", dir));
+
+        let mut tests: Vec<(&str, &str)> = Vec::new();
+        tests.push(("(require 'util.text)", "nil"));
+        tests.push(("*ns*", "user"));
+        tests.push(("(util.text/shout \"a\")", "a!"));
+
+        //;; loaded once, however many times it's required
+        tests.push(("(require 'util.text '[util.text :as u])", "nil"));
+        tests.push(("@u/loads", "1"));
+        tests.push(("(require 'app)", "nil"));
+        tests.push(("@util.text/loads", "1"));
+        tests.push(("(app/run)", "hi!!"));
+        tests.push(("(require '[app :refer :all])", "nil"));
+        tests.push(("(run)", "hi!!"));
+
+        //;; a module's functions expand its own macros
+        tests.push(("(require '[foo.bar :as fb])", "nil"));
+        tests.push(("(fb/use-unless false)", ":ran"));
+
+        tests.push(("(require 'a)", "circular require: a -> b -> a"));
+        tests.push(("*ns*", "user"));
+        tests.push(("(require 'missing.mod)", "require: can't find missing/mod.mal in *load-path*"));
+        tests.push(("(require '[app :as])", "require: bad option in [app :as]"));
+        tests.push(("(require \"app\")", "require takes namespace symbols or [name :as alias :refer [names]], not \"app\""));
+
+        //;; a failed load leaves nothing behind, so it can be required again once fixed
+        tests.push(("(require 'half)", "nope not found."));
+        tests.push(("half/before", "half/before not found."));
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), false), tup.1, "{}", tup.0);
+        }
+
+        let ast = read_str("(require 'nons)");
+        assert_eq!(
+            pr_str(&eval(&ast, &mut env), false),
+            format!("require: {}/nons.mal doesn't define namespace nons", dir)
+        );
+
+        ::std::fs::write(format!("{}/half.mal", dir), "(ns half) (def! before 2)").unwrap();
+        ::std::fs::write(format!("{}/b.mal", dir), "(ns b) (def! x 1)").unwrap();
+        let tests = vec![("(require 'half)", "nil"), ("half/before", "2"), ("(require 'a)", "nil"), ("b/x", "1")];
+        for tup in tests {
+            let ast = read_str(tup.0);
+            assert_eq!(pr_str(&eval(&ast, &mut env), false), tup.1, "{}", tup.0);
+        }
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
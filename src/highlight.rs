@@ -0,0 +1,242 @@
+use printer::{CONSTANT_COLOUR, KEYWORD_COLOUR, NUMBER_COLOUR, STRING_COLOUR};
+use reader::read_str;
+use rustyline;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::Helper;
+use std::borrow::Cow::{self, Borrowed, Owned};
+use types::MalType;
+
+const COMMENT_COLOUR: &str = "2";
+const MATCHING_BRACKET_COLOUR: &str = "1;34";
+
+//Highlights the REPL input line: literals get the same colours as printed
+//results, comments are dimmed and the bracket at the cursor is shown with its match
+pub struct MalHelper;
+
+impl Completer for MalHelper {
+    type Candidate = String;
+
+    fn complete(&self, _line: &str, _pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok((0, Vec::new()))
+    }
+}
+
+impl Hinter for MalHelper {
+    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+        None
+    }
+}
+
+impl Highlighter for MalHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut colours = token_colours(&chars);
+        if let Some((a, b)) = matching_brackets(&chars, &colours, line[..pos].chars().count()) {
+            colours[a] = Some(MATCHING_BRACKET_COLOUR);
+            colours[b] = Some(MATCHING_BRACKET_COLOUR);
+        }
+
+        if colours.iter().all(|c| c.is_none()) {
+            Borrowed(line)
+        } else {
+            Owned(paint_chars(&chars, &colours))
+        }
+    }
+
+    //any key can change a token's colour or which brackets match
+    fn highlight_char(&self, _grapheme: &str) -> bool {
+        true
+    }
+}
+
+impl Helper for MalHelper {}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "[](){}'`~^@\",;".contains(c)
+}
+
+fn literal_colour(value: &MalType) -> Option<&'static str> {
+    if value.is_int() || value.is_float() {
+        Some(NUMBER_COLOUR)
+    } else if value.is_keyword() {
+        Some(KEYWORD_COLOUR)
+    } else if value.is_nil() || value.is_bool() {
+        Some(CONSTANT_COLOUR)
+    } else {
+        None
+    }
+}
+
+//The colour of each char, None for brackets, whitespace and symbols
+fn token_colours(line: &[char]) -> Vec<Option<&'static str>> {
+    let mut colours = vec![None; line.len()];
+    let mut i = 0;
+
+    while i < line.len() {
+        let start = i;
+        let c = line[i];
+        let colour = if c == ';' {
+            i = line.len();
+            Some(COMMENT_COLOUR)
+        } else if c == '"' || (c == '#' && line.get(i + 1) == Some(&'"')) {
+            i += if c == '#' { 2 } else { 1 };
+            let mut escaped = false;
+            while i < line.len() {
+                let d = line[i];
+                i += 1;
+                if d == '"' && !escaped {
+                    break;
+                }
+                escaped = d == '\\' && !escaped;
+            }
+            Some(STRING_COLOUR)
+        } else if c == '\\' {
+            //a char literal is the backslash, any char, then up to a delimiter
+            i = (i + 2).min(line.len());
+            while i < line.len() && !is_delimiter(line[i]) {
+                i += 1;
+            }
+            Some(STRING_COLOUR)
+        } else if is_delimiter(c) {
+            i += 1;
+            None
+        } else {
+            while i < line.len() && !is_delimiter(line[i]) {
+                i += 1;
+            }
+            let token: String = line[start..i].iter().collect();
+            literal_colour(&read_str(&token))
+        };
+
+        for col in &mut colours[start..i] {
+            *col = colour;
+        }
+    }
+
+    colours
+}
+
+//The bracket under or just before the cursor and the one matching it
+fn matching_brackets(
+    line: &[char],
+    colours: &[Option<&str>],
+    cursor: usize,
+) -> Option<(usize, usize)> {
+    let is_bracket = |i: usize| "()[]{}".contains(line[i]) && colours[i].is_none();
+    let at = if cursor < line.len() && is_bracket(cursor) {
+        cursor
+    } else if cursor > 0 && cursor <= line.len() && is_bracket(cursor - 1) {
+        cursor - 1
+    } else {
+        return None;
+    };
+
+    let (open, close) = match line[at] {
+        '(' | ')' => ('(', ')'),
+        '[' | ']' => ('[', ']'),
+        _ => ('{', '}'),
+    };
+    let forward = line[at] == open;
+    let indices: Vec<usize> = if forward {
+        (at..line.len()).collect()
+    } else {
+        (0..at + 1).rev().collect()
+    };
+
+    let mut depth = 0;
+    for i in indices {
+        if !is_bracket(i) || (line[i] != open && line[i] != close) {
+            continue;
+        }
+        if (line[i] == open) == forward {
+            depth += 1;
+        } else {
+            depth -= 1;
+            if depth == 0 {
+                return Some((at, i));
+            }
+        }
+    }
+    None
+}
+
+fn paint_chars(line: &[char], colours: &[Option<&str>]) -> String {
+    let mut result = String::new();
+    let mut current = None;
+
+    for (c, colour) in line.iter().zip(colours) {
+        if *colour != current {
+            if current.is_some() {
+                result.push_str("\x1b[0m");
+            }
+            if let Some(col) = *colour {
+                result.push_str(&format!("\x1b[{}m", col));
+            }
+            current = *colour;
+        }
+        result.push(*c);
+    }
+    if current.is_some() {
+        result.push_str("\x1b[0m");
+    }
+
+    result
+}
+
+/*
+  Unit Tests for various functions/methods
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn token_colours_test() {
+        let line = chars("(f 1 :k \"a b\" nil) ;c");
+        let colours = token_colours(&line);
+        assert_eq!(None, colours[0]);
+        assert_eq!(None, colours[1]);
+        assert_eq!(Some(NUMBER_COLOUR), colours[3]);
+        assert_eq!(Some(KEYWORD_COLOUR), colours[5]);
+        assert_eq!(Some(STRING_COLOUR), colours[10]);
+        assert_eq!(Some(CONSTANT_COLOUR), colours[14]);
+        assert_eq!(Some(COMMENT_COLOUR), colours[20]);
+
+        let colours = token_colours(&chars("[\\a \"x"));
+        assert_eq!(
+            vec![None, Some(STRING_COLOUR), Some(STRING_COLOUR), None],
+            colours[..4].to_vec()
+        );
+        assert_eq!(Some(STRING_COLOUR), colours[5]);
+    }
+
+    #[test]
+    fn matching_brackets_test() {
+        let line = chars("(a [b \")\"] (c))");
+        let colours = token_colours(&line);
+        assert_eq!(Some((0, 14)), matching_brackets(&line, &colours, 0));
+        assert_eq!(Some((14, 0)), matching_brackets(&line, &colours, 15));
+        assert_eq!(Some((3, 9)), matching_brackets(&line, &colours, 3));
+        assert_eq!(Some((13, 11)), matching_brackets(&line, &colours, 13));
+        assert_eq!(None, matching_brackets(&line, &colours, 2));
+
+        let line = chars("(a (b)");
+        let colours = token_colours(&line);
+        assert_eq!(None, matching_brackets(&line, &colours, 0));
+    }
+
+    #[test]
+    fn highlight_test() {
+        assert_eq!("(a b)", MalHelper.highlight("(a b)", 2));
+        assert_eq!(
+            "\x1b[1;34m(\x1b[0ma \x1b[36m1\x1b[0m\x1b[1;34m)\x1b[0m",
+            MalHelper.highlight("(a 1)", 5)
+        );
+    }
+}
@@ -1,14 +1,21 @@
+extern crate libc;
 extern crate regex;
 extern crate rustyline;
 
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
 use std::env;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::process;
+use std::thread;
 
+mod cli;
 mod core;
 mod eval;
+mod highlight;
 mod printer;
 mod reader;
+mod repl;
 mod types;
 
 use types::MalType;
@@ -20,51 +27,127 @@ pub fn rep(line: &str, env: &mut eval::Environment) -> String {
     printer::pr_str(&result, true)
 }
 
-const HISTORY_FILE: &str = ".history.txt";
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
+//A fresh root environment with the builtins, prelude, and *ARGV* and *load-path* from the command line
+pub fn new_environment(opts: &cli::Options) -> eval::Environment {
     let mut env = eval::Environment::new();
     core::init_environment(&mut env);
 
-    if args.len() > 1 {
-        let file = &args[1];
-        let mut argv: Vec<MalType> = Vec::new();
+    let argv: Vec<MalType> = opts
+        .argv
+        .iter()
+        .map(|a| MalType::string(a.clone()))
+        .collect();
+    env.set("*ARGV*", MalType::list(argv));
 
-        for arg in &args[2..] {
-            argv.push(MalType::string(arg.clone()));
-        }
+    //require looks beside the script, or in the current directory, before MAL_PATH
+    let base = match opts.script {
+        Some(ref script) if script != "-" => Path::new(script)
+            .parent()
+            .map_or(".".to_string(), |dir| dir.to_string_lossy().into_owned()),
+        _ => ".".to_string(),
+    };
+    let base = if base.is_empty() { ".".to_string() } else { base };
+    let mut load_path = vec![MalType::string(base)];
+    load_path.extend(env.get("*load-path*").get_list().iter().cloned());
+    env.set("*load-path*", MalType::vector(load_path));
+    env
+}
 
-        env.set("*ARGV*", MalType::list(argv));
+//Evaluates code without echoing results, Err holds the message for stderr
+fn run_source(result: MalType) -> Result<MalType, String> {
+    if result.is_error() {
+        Err(printer::pr_str(&result, false))
+    } else {
+        Ok(result)
+    }
+}
 
-        println!("{}", rep(&format!("(load-file \"{}\"", file), &mut env));
+//Runs a script file, or stdin when the path is `-`
+fn run_script(path: &str, env: &mut eval::Environment) -> Result<MalType, String> {
+    if path == "-" {
+        let mut source = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut source) {
+            return Err(format!("stdin: {}", e));
+        }
+        run_source(core::eval_source(&source, env))
     } else {
-        // `()` can be used when no completer is required
-        let mut rl = Editor::<()>::new();
-        if rl.load_history(HISTORY_FILE).is_err() {
-            println!("No previous history.");
+        run_source(core::load_file(env, path))
+    }
+}
+
+//Loads, runs the script and -e expressions, then the REPL, returning the exit status
+fn run(opts: &cli::Options, env: &mut eval::Environment) -> i32 {
+    for file in &opts.loads {
+        if let Err(msg) = run_source(core::load_file(env, file)) {
+            eprintln!("Error: {}", msg);
+            return 1;
+        }
+    }
+
+    if let Some(ref script) = opts.script {
+        if let Err(msg) = run_script(script, env) {
+            eprintln!("Error: {}", msg);
+            return 1;
+        }
+    }
+
+    for expr in &opts.evals {
+        match run_source(core::eval_source(expr, env)) {
+            //like clojure.main, -e prints any non-nil result
+            Ok(ref result) if !result.is_nil() && core::exit_status().is_none() => {
+                println!("{}", printer::pr_str_limited(result, true))
+            }
+            Ok(_) => {}
+            Err(msg) => {
+                eprintln!("Error: {}", msg);
+                return 1;
+            }
         }
-        loop {
-            let readline = rl.readline("user> ");
-            match readline {
-                Ok(line) => {
-                    rl.add_history_entry(line.as_ref());
-                    println!("{}", rep(&line, &mut env));
-                }
-                Err(ReadlineError::Interrupted) => {
-                    println!("CTRL-C");
-                    break;
-                }
-                Err(ReadlineError::Eof) => {
-                    println!("CTRL-D");
-                    break;
-                }
-                Err(err) => {
-                    println!("Error: {:?}", err);
-                    break;
-                }
+    }
+
+    if opts.interactive() && core::exit_status().is_none() {
+        if let Some(init) = opts.init_file_path() {
+            if let Err(msg) = run_source(core::load_file(env, &init.to_string_lossy())) {
+                eprintln!("Error: {}", msg);
             }
         }
-        rl.save_history(HISTORY_FILE).unwrap();
+        if core::exit_status().is_none() {
+            repl::run_repl(opts, env);
+        }
+    }
+
+    0
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = match cli::parse_args(&args[1..]) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("mal: {}\n{}", msg, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if opts.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if opts.version {
+        println!("mal {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    //the main thread's stack is too small for deep, non-tail recursion
+    let interpreter = thread::Builder::new()
+        .stack_size(eval::STACK_SIZE)
+        .spawn(move || {
+            let mut env = new_environment(&opts);
+            let status = run(&opts, &mut env);
+            core::exit_status().unwrap_or(status)
+        });
+    match interpreter.map(|handle| handle.join()) {
+        Ok(Ok(status)) => process::exit(status),
+        _ => process::exit(1),
     }
 }
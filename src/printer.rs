@@ -1,103 +1,656 @@
-use types::MalType;
+use eval::Environment;
+use std::cell::RefCell;
+use std::rc::Rc;
+use types::{MalEnum, MalType};
 
 fn escape(s: &str) -> String {
-    let len = s.len();
-
-    if len > 0 {
-        let mut result = "\"".to_string();
-        let fixed = s
-            .replace("\\", "\\\\")
-            .replace("\n", "\\n")
-            .replace("\t", "\\t")
-            .replace("\"", "\\\"");
-        result.push_str(&fixed);
-        result.push('"');
-
-        result
-    } else {
-        s.to_string()
+    let mut result = "\"".to_string();
+
+    for c in s.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '"' => result.push_str("\\\""),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
     }
+    result.push('"');
+
+    result
 }
 
-pub fn pr_str(t: &MalType, print_readably: bool) -> String {
-    //println!("{:?}",t);
-
-    if t.is_nil() {
-        "nil".to_string()
-    } else if t.is_int() {
-        t.get_int().to_string()
-    } else if t.is_float() {
-        t.get_float().to_string()
-    } else if t.is_bool() {
-        t.get_bool().to_string()
-    } else if t.is_string() {
-        let s = t.get_string();
-        if print_readably {
-            escape(&s)
+//Floats always show a decimal point or exponent so they read back as floats
+fn float_str(f: f64) -> String {
+    if f.is_nan() {
+        "##NaN".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 {
+            "##Inf".to_string()
         } else {
-            s.to_string()
+            "##-Inf".to_string()
         }
-    } else if t.is_symbol() {
-        t.get_string().to_string()
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+//Quotes a regex pattern as a #"..." literal, escaping any bare double quotes
+fn escape_regex(s: &str) -> String {
+    let mut result = "#\"".to_string();
+    let mut escaped = false;
+
+    for c in s.chars() {
+        if c == '"' && !escaped {
+            result.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        result.push(c);
+    }
+    result.push('"');
+
+    result
+}
+
+//Names for the characters that can't be written directly after a backslash
+pub const CHAR_NAMES: [(char, &str); 6] = [
+    ('\n', "newline"),
+    (' ', "space"),
+    ('\t', "tab"),
+    ('\r', "return"),
+    ('\u{8}', "backspace"),
+    ('\u{c}', "formfeed"),
+];
+
+fn char_name(c: char) -> Option<&'static str> {
+    CHAR_NAMES.iter().find(|n| n.0 == c).map(|n| n.1)
+}
+
+thread_local! {
+    //The environment *print-length* and *print-level* are looked up in
+    static SETTINGS_ENV: RefCell<Option<Environment>> = RefCell::new(None);
+}
+
+pub fn set_settings_env(env: &Environment) {
+    SETTINGS_ENV.with(|s| *s.borrow_mut() = Some(env.clone()));
+}
+
+//ANSI colours for highlighted output
+pub const NUMBER_COLOUR: &str = "36";
+pub const STRING_COLOUR: &str = "32";
+pub const KEYWORD_COLOUR: &str = "35";
+pub const SYMBOL_COLOUR: &str = "34";
+pub const CONSTANT_COLOUR: &str = "33";
+pub const ERROR_COLOUR: &str = "1;31";
+
+fn highlight_colour(t: &MalType) -> Option<&'static str> {
+    if t.is_int() || t.is_float() {
+        Some(NUMBER_COLOUR)
+    } else if t.is_string() || t.is_char() || t.is_regex() {
+        Some(STRING_COLOUR)
     } else if t.is_keyword() {
-        t.get_string().to_string()
-    } else if t.is_atom() {
-        let s = t.get_atom();
-        let mut result = String::new();
-        result.push_str("(atom ");
-
-        result.push_str(&pr_str(&s, print_readably));
-        result.push_str(")");
-        result
-    } else if t.is_list() {
+        Some(KEYWORD_COLOUR)
+    } else if t.is_symbol() {
+        Some(SYMBOL_COLOUR)
+    } else if t.is_nil() || t.is_bool() {
+        Some(CONSTANT_COLOUR)
+    } else if t.is_error() {
+        Some(ERROR_COLOUR)
+    } else {
+        None
+    }
+}
+
+pub fn paint(colour: &str, s: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", colour, s)
+}
+
+//Columns s takes on screen, not counting ANSI colour codes
+fn display_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+
+    for c in s.chars() {
+        if in_escape {
+            in_escape = c != 'm';
+        } else if c == '\x1b' {
+            in_escape = true;
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+//A non-negative int setting, None when it's nil or unset
+fn limit_setting(name: &str) -> Option<usize> {
+    SETTINGS_ENV.with(|s| match *s.borrow() {
+        Some(ref env) => match env.find(name) {
+            Some(ref v) if v.is_int() && v.get_int() >= 0 => Some(v.get_int() as usize),
+            _ => None,
+        },
+        None => None,
+    })
+}
+
+//State for printing one value: the *print-length* and *print-level* limits
+//when printing for display, how deep in collections we are and the atoms being
+//printed, so a cycle through an atom prints as (atom ...) instead of recursing forever
+struct Printer {
+    print_readably: bool,
+    highlight: bool,
+    length: Option<usize>,
+    level: Option<usize>,
+    depth: usize,
+    atoms: Vec<Rc<RefCell<MalEnum>>>,
+}
+
+impl Printer {
+    fn new(print_readably: bool, highlight: bool, limited: bool) -> Printer {
+        Printer {
+            print_readably,
+            highlight,
+            length: if limited {
+                limit_setting("*print-length*")
+            } else {
+                None
+            },
+            level: if limited {
+                limit_setting("*print-level*")
+            } else {
+                None
+            },
+            depth: 0,
+            atoms: Vec::new(),
+        }
+    }
+
+    fn too_deep(&self) -> bool {
+        self.level.map_or(false, |level| self.depth >= level)
+    }
+
+    //The items to show, and whether the rest are elided. Maps count entries as pairs.
+    fn visible<'a>(&self, items: &'a [MalType], per_entry: usize) -> (&'a [MalType], bool) {
+        match self.length {
+            Some(n) if items.len() > n * per_entry => (&items[..n * per_entry], true),
+            _ => (items, false),
+        }
+    }
+
+    fn in_cycle(&self, t: &MalType) -> bool {
+        self.atoms.iter().any(|a| Rc::ptr_eq(a, &t.val))
+    }
+
+    fn print_seq(&mut self, open: &str, t: &MalType, close: &str, per_entry: usize) -> String {
+        if self.too_deep() {
+            return "...".to_string();
+        }
+
         let l = t.get_list();
-        let mut result = String::new();
-        result.push_str("(");
+        let (shown, elided) = self.visible(&l, per_entry);
+        self.depth += 1;
+        let mut items: Vec<String> = shown.iter().map(|item| self.print(item)).collect();
+        self.depth -= 1;
+        if elided {
+            items.push("...".to_string());
+        }
 
-        for (i, item) in l.iter().enumerate() {
-            if i > 0 {
-                result.push_str(" ");
+        format!("{}{}{}", open, items.join(" "), close)
+    }
+
+    fn print(&mut self, t: &MalType) -> String {
+        let s = self.print_value(t);
+        match highlight_colour(t) {
+            Some(colour) if self.highlight => paint(colour, &s),
+            _ => s,
+        }
+    }
+
+    fn print_value(&mut self, t: &MalType) -> String {
+        let print_readably = self.print_readably;
+
+        if t.is_nil() {
+            "nil".to_string()
+        } else if t.is_int() {
+            t.get_int().to_string()
+        } else if t.is_float() {
+            float_str(t.get_float())
+        } else if t.is_bool() {
+            t.get_bool().to_string()
+        } else if t.is_char() {
+            let c = t.get_char();
+            if print_readably {
+                match char_name(c) {
+                    Some(name) => format!("\\{}", name),
+                    None if c.is_control() => format!("\\u{:04x}", c as u32),
+                    None => format!("\\{}", c),
+                }
+            } else {
+                c.to_string()
+            }
+        } else if t.is_string() {
+            let s = t.get_string();
+            if print_readably {
+                escape(&s)
+            } else {
+                s.to_string()
+            }
+        } else if t.is_symbol() {
+            t.get_string().to_string()
+        } else if t.is_keyword() {
+            t.get_string().to_string()
+        } else if t.is_atom() {
+            if self.in_cycle(t) {
+                return "(atom ...)".to_string();
+            }
+            self.atoms.push(t.val.clone());
+            let result = format!("(atom {})", self.print(&t.get_atom()));
+            self.atoms.pop();
+            result
+        } else if t.is_list() {
+            self.print_seq("(", t, ")", 1)
+        } else if t.is_vector() {
+            self.print_seq("[", t, "]", 1)
+        } else if t.is_map() {
+            self.print_seq("{", t, "}", 2)
+        } else if t.is_regex() {
+            let re = t.get_regex();
+            if print_readably {
+                escape_regex(re.as_str())
+            } else {
+                re.as_str().to_string()
+            }
+        } else if t.is_handle() {
+            let h = t.get_handle();
+            if h.is_closed() {
+                format!("#<handle {} (closed)>", h.name)
+            } else {
+                format!("#<handle {}>", h.name)
             }
-            result.push_str(&pr_str(item, print_readably));
+        } else if t.is_error() {
+            (*t.get_string()).to_string()
+        } else if t.is_func() {
+            if t.is_macro() {
+                "#<builtin macro>".to_string()
+            } else {
+                "#<builtin function>".to_string()
+            }
+        } else if t.is_func_tco() {
+            if t.is_macro() {
+                "#<macro>".to_string()
+            } else {
+                "#<function>".to_string()
+            }
+        } else {
+            "".to_string()
         }
+    }
 
-        result.push_str(")");
-        result
-    } else if t.is_vector() {
-        let l = t.get_list();
-        let mut result = String::new();
-        result.push_str("[");
+    fn seq_doc(&mut self, open: &str, t: &MalType, close: &str, layout: &Layout) -> Doc {
+        if self.too_deep() {
+            return Doc::Text("...".to_string());
+        }
 
-        for (i, item) in l.iter().enumerate() {
+        let l = t.get_list();
+        let (shown, elided) = self.visible(&l, 1);
+        let mut parts = Vec::new();
+        self.depth += 1;
+        for (i, item) in shown.iter().enumerate() {
             if i > 0 {
-                result.push_str(" ");
+                parts.push(Doc::Line);
+            }
+            parts.push(self.doc(item, layout));
+        }
+        self.depth -= 1;
+        if elided {
+            if !parts.is_empty() {
+                parts.push(Doc::Line);
             }
-            result.push_str(&pr_str(item, print_readably));
+            parts.push(Doc::Text("...".to_string()));
+        }
+
+        Doc::Group(Box::new(Doc::Concat(vec![
+            Doc::Text(open.to_string()),
+            Doc::Align(Box::new(Doc::Concat(parts))),
+            Doc::Text(close.to_string()),
+        ])))
+    }
+
+    //Key value pairs go one per line when the map is broken, with the values lined
+    //up when align_maps is set
+    fn map_doc(&mut self, t: &MalType, layout: &Layout) -> Doc {
+        if self.too_deep() {
+            return Doc::Text("...".to_string());
         }
 
-        result.push_str("]");
-        result
-    } else if t.is_map() {
         let l = t.get_list();
-        let mut result = String::new();
-        result.push_str("{");
+        let (shown, elided) = self.visible(&l, 2);
+        let mut parts = Vec::new();
+        self.depth += 1;
+        let key_widths: Vec<usize> = shown
+            .iter()
+            .step_by(2)
+            .map(|k| display_width(&self.print(k)))
+            .collect();
+        let widest = key_widths.iter().cloned().max().unwrap_or(0);
 
-        for (i, item) in l.iter().enumerate() {
+        for (i, pair) in shown.chunks(2).enumerate() {
             if i > 0 {
-                result.push_str(" ");
+                parts.push(Doc::Line);
+            }
+            parts.push(self.doc(&pair[0], layout));
+            if layout.align_maps {
+                parts.push(Doc::IfBreak(" ".repeat(widest - key_widths[i])));
             }
-            result.push_str(&pr_str(item, print_readably));
+            if let Some(v) = pair.get(1) {
+                parts.push(Doc::Text(" ".to_string()));
+                parts.push(self.doc(v, layout));
+            }
+        }
+        self.depth -= 1;
+        if elided {
+            if !parts.is_empty() {
+                parts.push(Doc::Line);
+            }
+            parts.push(Doc::Text("...".to_string()));
         }
 
-        result.push_str("}");
-        result
-    } else if t.is_error() {
-        (*t.get_string()).to_string()
-    } else if t.is_func() {
-        format!("#<function>: is_macro({})", t.is_macro())
-    } else if t.is_func_tco() {
-        format!("#<functionTCO>: is_macro({})", t.is_macro())
-    } else {
-        "".to_string()
+        Doc::Group(Box::new(Doc::Concat(vec![
+            Doc::Text("{".to_string()),
+            Doc::Align(Box::new(Doc::Concat(parts))),
+            Doc::Text("}".to_string()),
+        ])))
+    }
+
+    fn doc(&mut self, t: &MalType, layout: &Layout) -> Doc {
+        if t.is_list() {
+            self.seq_doc("(", t, ")", layout)
+        } else if t.is_vector() {
+            self.seq_doc("[", t, "]", layout)
+        } else if t.is_map() {
+            self.map_doc(t, layout)
+        } else if t.is_atom() && !self.in_cycle(t) {
+            self.atoms.push(t.val.clone());
+            let inner = self.doc(&t.get_atom(), layout);
+            self.atoms.pop();
+            Doc::Concat(vec![
+                Doc::Text("(atom ".to_string()),
+                Doc::Align(Box::new(inner)),
+                Doc::Text(")".to_string()),
+            ])
+        } else {
+            Doc::Text(self.print(t))
+        }
+    }
+}
+
+//The whole value, for building strings and files from data
+pub fn pr_str(t: &MalType, print_readably: bool) -> String {
+    Printer::new(print_readably, false, false).print(t)
+}
+
+//The value as shown to a reader, eliding what's past *print-length* and *print-level*
+pub fn pr_str_limited(t: &MalType, print_readably: bool) -> String {
+    Printer::new(print_readably, false, true).print(t)
+}
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+//Settings for pprint_str
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub width: usize,
+    pub align_maps: bool,
+    pub highlight: bool,
+}
+
+//A Wadler-style document: groups print on one line when they fit, otherwise
+//every Line in the group becomes a newline
+enum Doc {
+    Text(String),
+    //a space, or a newline when the enclosing group is broken
+    Line,
+    //text that only appears when the enclosing group is broken
+    IfBreak(String),
+    //indents lines inside it to the column where it starts
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+//Whether doc printed flat, followed by the rest of the line, fits in remaining columns
+fn fits(mut remaining: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut pending: Vec<(bool, &Doc)> = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+
+    while remaining >= 0 {
+        let (flat, d) = match pending.pop() {
+            Some(p) => p,
+            None => match rest.next() {
+                Some(&(_, flat, d)) => (flat, d),
+                None => return true,
+            },
+        };
+        match *d {
+            Doc::Text(ref s) => remaining -= display_width(s) as isize,
+            Doc::Line if flat => remaining -= 1,
+            Doc::Line => return true,
+            Doc::IfBreak(ref s) if !flat => remaining -= display_width(s) as isize,
+            Doc::IfBreak(_) => {}
+            Doc::Align(ref d) | Doc::Group(ref d) => pending.push((flat, d)),
+            Doc::Concat(ref ds) => pending.extend(ds.iter().rev().map(|d| (flat, d))),
+        }
+    }
+    false
+}
+
+fn render(doc: &Doc, width: usize) -> String {
+    let mut result = String::new();
+    let mut column = 0;
+    //(indent, flat, doc) with the next piece to print on top
+    let mut stack: Vec<(usize, bool, &Doc)> = vec![(0, false, doc)];
+
+    while let Some((indent, flat, d)) = stack.pop() {
+        match *d {
+            Doc::Text(ref s) => {
+                result.push_str(s);
+                column += display_width(s);
+            }
+            Doc::Line if flat => {
+                result.push(' ');
+                column += 1;
+            }
+            Doc::Line => {
+                result.push('\n');
+                result.push_str(&" ".repeat(indent));
+                column = indent;
+            }
+            Doc::IfBreak(ref s) if !flat => {
+                result.push_str(s);
+                column += display_width(s);
+            }
+            Doc::IfBreak(_) => {}
+            Doc::Align(ref d) => stack.push((column, flat, d)),
+            Doc::Group(ref d) => {
+                let flat = flat || fits(width as isize - column as isize, d, &stack);
+                stack.push((indent, flat, d));
+            }
+            Doc::Concat(ref ds) => stack.extend(ds.iter().rev().map(|d| (indent, flat, d))),
+        }
+    }
+
+    result
+}
+
+//Like pr_str, but breaks collections over indented lines to fit layout.width
+pub fn pprint_str(t: &MalType, print_readably: bool, layout: &Layout) -> String {
+    let mut printer = Printer::new(print_readably, layout.highlight, true);
+    render(&printer.doc(t, layout), layout.width)
+}
+
+/*
+  Unit Tests for various functions/methods
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reader::read_str;
+
+    fn pprint(text: &str, width: usize, align_maps: bool) -> String {
+        let layout = Layout {
+            width,
+            align_maps,
+            highlight: false,
+        };
+        pprint_str(&read_str(text), true, &layout)
+    }
+
+    #[test]
+    fn pprint_str_test() {
+        assert_eq!("(1 2 3)", pprint("(1 2 3)", 7, false));
+        assert_eq!("(1\n 2\n 3)", pprint("(1 2 3)", 6, false));
+        assert_eq!("\"a b\"", pprint("\"a b\"", 1, false));
+        let mut layout = Layout {
+            width: 10,
+            align_maps: false,
+            highlight: false,
+        };
+        assert_eq!("a b", pprint_str(&read_str("\"a b\""), false, &layout));
+
+        assert_eq!(
+            "(defn\n f\n [a b]\n (+ a b))",
+            pprint("(defn f [a b] (+ a b))", 20, false)
+        );
+        assert_eq!(
+            "[(+ 1 2)\n [:a :b :c]\n {:x 1 :y 2}]",
+            pprint("[(+ 1 2) [:a :b :c] {:x 1 :y 2}]", 16, false)
+        );
+        assert_eq!(
+            "{:a 1\n :bcd [1 2 3]\n :ef {:g 1\n      :h 2}}",
+            pprint("{:a 1 :bcd [1 2 3] :ef {:g 1 :h 2}}", 14, false)
+        );
+        assert_eq!(
+            "{:a   1\n :bcd [1 2 3]\n :ef  {:g 1 :h 2}}",
+            pprint("{:a 1 :bcd [1 2 3] :ef {:g 1 :h 2}}", 20, true)
+        );
+        assert_eq!("{:a 1 :bb 2}", pprint("{:a 1 :bb 2}", 20, true));
+
+        let mut a = MalType::atom(read_str("[1 2]"));
+        assert_eq!("(atom [1\n       2])", pprint_str(&a, true, &layout));
+        a.set_atom(a.clone());
+        assert_eq!("(atom (atom ...))", pprint_str(&a, true, &layout));
+
+        layout.highlight = true;
+        assert_eq!(
+            "(\x1b[36m1\x1b[0m \x1b[35m:a\x1b[0m \x1b[32m\"b\"\x1b[0m)",
+            pprint_str(&read_str("(1 :a \"b\")"), true, &layout)
+        );
+        assert_eq!(
+            "[\x1b[34mx\x1b[0m \x1b[33mnil\x1b[0m]",
+            pprint_str(&read_str("[x nil]"), true, &layout)
+        );
+    }
+
+    //A small xorshift generator so the property test needs no extra crates
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    fn random_char(rng: &mut Rng) -> char {
+        match rng.below(4) {
+            0 => ::std::char::from_u32(rng.below(0x20) as u32).unwrap(),
+            1 => rng
+                .pick(&[
+                    "\\", "\"", ";", "(", "é", "λ", "中", "\u{a0}", "\u{85}", "\u{2028}", "😀",
+                ])
+                .chars()
+                .next()
+                .unwrap(),
+            _ => ::std::char::from_u32(0x20 + rng.below(0x5f) as u32).unwrap(),
+        }
+    }
+
+    fn random_float(rng: &mut Rng) -> f64 {
+        match rng.below(4) {
+            0 => f64::from(rng.below(1000) as u32),
+            1 => [0.5, -0.0, 1e300, -2.5e-8, f64::INFINITY, f64::NEG_INFINITY][rng.below(6)],
+            _ => {
+                let f = f64::from_bits(rng.next());
+                if f.is_nan() {
+                    0.1
+                } else {
+                    f
+                }
+            }
+        }
+    }
+
+    //Any value made of data: scalars, strings, chars, symbols, keywords and collections
+    fn random_value(rng: &mut Rng, depth: usize) -> MalType {
+        let kinds = if depth == 0 { 8 } else { 11 };
+        match rng.below(kinds) {
+            0 => MalType::nil(),
+            1 => MalType::bool(rng.below(2) == 0),
+            2 => MalType::int(rng.next() as i64 >> rng.below(64)),
+            3 => MalType::float(random_float(rng)),
+            4 => {
+                let len = rng.below(6);
+                MalType::string((0..len).map(|_| random_char(rng)).collect())
+            }
+            5 => MalType::char(random_char(rng)),
+            6 => MalType::symbol(
+                rng.pick(&[
+                    "a", "foo-bar", "+", "*x*", "->>", "a.b/c", "ok?", "inf", "nan",
+                ])
+                .to_string(),
+            ),
+            7 => MalType::keyword(rng.pick(&[":a", ":foo-bar", ":x/y", ":+"]).to_string()),
+            kind => {
+                let len = rng.below(5) * if kind == 10 { 2 } else { 1 };
+                let items = (0..len).map(|_| random_value(rng, depth - 1)).collect();
+                match kind {
+                    8 => MalType::list(items),
+                    9 => MalType::vector(items),
+                    _ => MalType::map(items),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pr_str_round_trip_test() {
+        assert_eq!("\"\"", pr_str(&MalType::string(String::new()), true));
+        assert_eq!(
+            "\"a\\r\\u0007\"",
+            pr_str(&read_str("\"a\\r\\u0007\""), true)
+        );
+        assert_eq!("1.0", pr_str(&MalType::float(1.0), true));
+        assert_eq!("##-Inf", pr_str(&MalType::float(f64::NEG_INFINITY), true));
+        assert!(read_str("#<function>").is_error());
+        assert!(read_str("\"abc").is_error());
+
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let value = random_value(&mut rng, 3);
+            let printed = pr_str(&value, true);
+            assert_eq!(value, read_str(&printed), "{}", printed);
+        }
     }
 }
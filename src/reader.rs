@@ -1,6 +1,7 @@
-use regex::Captures;
 use regex::Regex;
+use printer::CHAR_NAMES;
 use std::str::FromStr;
+use types::compile_regex;
 use types::MalType;
 
 pub const TOKEN_LEFT_PAREN: &str = "(";
@@ -78,37 +79,119 @@ fn parsable<T: FromStr>(s: &str) -> bool {
     s.parse::<T>().is_ok()
 }
 
+//Undoes printer::escape, taking a token that has both quotes
 fn unescape_str(s: &str) -> String {
-    let re: Regex = Regex::new(r#"\\(.)"#).unwrap();
-    let temp = re.replace_all(&s, |caps: &Captures| {
-        if &caps[1] == "n" {
-            "\n"
-        } else if &caps[1] == "t" {
-            "\t"
-        } else if &caps[1] == "\\" {
-            "\\"
+    let mut result = String::new();
+    let mut chars = s[1..(s.len() - 1)].chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+                    Some(u) => result.push(u),
+                    None => {
+                        result.push('u');
+                        result.push_str(&hex);
+                    }
+                }
+            }
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+
+    result
+}
+
+fn read_regex(t: &str) -> MalType {
+    let l = t.len();
+    if l < 3 || !t.ends_with('"') {
+        return MalType::error("expected '\"', got EOF".to_string());
+    }
+
+    //only \" is special inside a #"..." literal, other escapes belong to the regex
+    let mut pattern = String::new();
+    let mut chars = t[2..(l - 1)].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if chars.peek() == Some(&'"') {
+                continue;
+            }
+            pattern.push(c);
+            if let Some(n) = chars.next() {
+                pattern.push(n);
+            }
         } else {
-            &caps[1]
-        }.to_string()
-    });
-    let l = temp.len();
+            pattern.push(c);
+        }
+    }
 
-    //println!("unescape_str: {}",temp);
+    match compile_regex(&pattern) {
+        Ok(re) => MalType::regex(re),
+        Err(e) => MalType::error(e),
+    }
+}
+
+fn read_char(t: &str) -> MalType {
+    let name = &t[1..];
+    let mut chars = name.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return MalType::char(c),
+        (None, _) => return MalType::error("expected character after \\".to_string()),
+        _ => {}
+    }
+
+    if let Some(n) = CHAR_NAMES.iter().find(|n| n.1 == name) {
+        return MalType::char(n.0);
+    }
+
+    if name.starts_with('u') && name.len() == 5 {
+        if let Some(c) = u32::from_str_radix(&name[1..], 16)
+            .ok()
+            .and_then(::std::char::from_u32)
+        {
+            return MalType::char(c);
+        }
+    }
 
-    temp[1..(l-1)].to_string()
+    MalType::error(format!("unsupported character: {}", t))
 }
 
 fn read_atom(reader: &mut Reader) -> MalType {
     //println!("read_atom: {:?}", reader.peek());
     match reader.next() {
+        Some("##Inf") => MalType::float(f64::INFINITY),
+        Some("##-Inf") => MalType::float(f64::NEG_INFINITY),
+        Some("##NaN") => MalType::float(f64::NAN),
         Some(t) if parsable::<i64>(t) => MalType::int(t.parse().unwrap()),
-        Some(t) if parsable::<f64>(t) => MalType::float(t.parse().unwrap()),
+        //f64 also parses words like inf and nan, those stay symbols
+        Some(t) if parsable::<f64>(t) && t.chars().any(|c| c.is_digit(10)) => {
+            MalType::float(t.parse().unwrap())
+        }
         Some(t) if parsable::<bool>(t) => MalType::bool(t.parse().unwrap()),
         Some(t) => {
             let first_char = t.chars().next().unwrap();
             if first_char == '\"' {
-                //MalType::Str(t.to_string())
-                MalType::string(unescape_str(t))
+                if is_terminated_string(t) {
+                    MalType::string(unescape_str(t))
+                } else {
+                    MalType::error("expected '\"', got EOF".to_string())
+                }
+            } else if t.starts_with("#<") {
+                MalType::error(format!("unreadable form {}", t))
+            } else if t.starts_with("#\"") {
+                read_regex(t)
+            } else if first_char == '\\' {
+                read_char(t)
             } else if first_char == ':' {
                 MalType::keyword(t.to_string())
             } else if t == "nil" {
@@ -165,9 +248,92 @@ pub fn read_str(line: &str) -> MalType {
     read_form(&mut r)
 }
 
+fn is_terminated_string(token: &str) -> bool {
+    let body = token.trim_start_matches('#');
+    if body.len() < 2 || !body.ends_with('"') {
+        return false;
+    }
+    let backslashes = body[1..body.len() - 1]
+        .chars()
+        .rev()
+        .take_while(|c| *c == '\\')
+        .count();
+    backslashes % 2 == 0
+}
+
+//True when the text stops inside a string or an unclosed list, vector or map
+pub fn is_incomplete(line: &str) -> bool {
+    let mut depth = 0;
+
+    for token in tokenizer(line) {
+        match token.as_str() {
+            TOKEN_LEFT_PAREN | TOKEN_LEFT_BRACKET | TOKEN_LEFT_CURLY => depth += 1,
+            TOKEN_RIGHT_PAREN | TOKEN_RIGHT_BRACKET | TOKEN_RIGHT_CURLY => depth -= 1,
+            t if t.starts_with('"') || t.starts_with("#\"") => {
+                if !is_terminated_string(t) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    depth > 0
+}
+
+//Reads every top level form in the text, e.g. the contents of a file,
+//which must close every list, vector, map and string it opens
+pub fn read_all(line: &str) -> Result<Vec<MalType>, MalType> {
+    let tokens = tokenizer(line);
+    check_balanced(&tokens)?;
+
+    let mut r = Reader::new(tokens);
+    let mut forms: Vec<MalType> = Vec::new();
+
+    while r.peek().is_some() {
+        forms.push(read_form(&mut r));
+    }
+
+    Ok(forms)
+}
+
+fn check_balanced(tokens: &[String]) -> Result<(), MalType> {
+    let mut open: Vec<&str> = Vec::new();
+
+    for token in tokens {
+        match token.as_str() {
+            TOKEN_LEFT_PAREN => open.push(TOKEN_RIGHT_PAREN),
+            TOKEN_LEFT_BRACKET => open.push(TOKEN_RIGHT_BRACKET),
+            TOKEN_LEFT_CURLY => open.push(TOKEN_RIGHT_CURLY),
+            TOKEN_RIGHT_PAREN | TOKEN_RIGHT_BRACKET | TOKEN_RIGHT_CURLY => {
+                if open.pop() != Some(token.as_str()) {
+                    return Err(MalType::error(format!(
+                        "unbalanced input: unexpected '{}'",
+                        token
+                    )));
+                }
+            }
+            t if (t.starts_with('"') || t.starts_with("#\"")) && !is_terminated_string(t) => {
+                return Err(MalType::error(
+                    "unbalanced input: expected '\"', got EOF".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some(close) => Err(MalType::error(format!(
+            "unbalanced input: expected '{}', got EOF",
+            close
+        ))),
+        None => Ok(()),
+    }
+}
+
 pub fn tokenizer(line: &str) -> Vec<String> {
     let re: Regex =
-        Regex::new(r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###)
+        Regex::new(r###"[\s,]*(~@|[\[\]{}()'`~^@]|#?"(?:\\.|[^\\"])*"?|;.*|\\.[^\s\[\]{}('"`,;)]*|[^\s\[\]{}('"`,;)]+)"###)
             .unwrap();
     let mut v: Vec<String> = Vec::new();
 
@@ -202,6 +368,7 @@ mod tests {
         assert_eq!(vec!["[", "]"], tokenizer("[]"));
         assert_eq!(vec!["{", "}"], tokenizer("{}"));
         assert_eq!(vec!["\"abc\""], tokenizer("\"abc\""));
+        assert_eq!(vec!["#\"a\\d+\""], tokenizer("#\"a\\d+\""));
         //assert_eq!(vec!["~@(", "1", "2", "3", ")"], tokenizer("~@(1 2 3)"));
         assert_eq!(Vec::<String>::new(), tokenizer(";this is a test"));
         assert_eq!(
@@ -340,5 +507,53 @@ mod tests {
         v1.push(MalType::string("boo".to_string()));
 
         assert_eq!(MalType::list(v1), read_str("(- (+ 1 a) 234.3 \"boo\")"));
+
+        assert_eq!(
+            Ok(vec![MalType::int(1), MalType::list(vec![MalType::int(2)])]),
+            read_all("1 ;comment\n(2)\n")
+        );
+        assert_eq!(Ok(Vec::<MalType>::new()), read_all(" ;; only a comment"));
+        assert_eq!(
+            Err(MalType::error(
+                "unbalanced input: expected ')', got EOF".to_string()
+            )),
+            read_all("(+ 1 2")
+        );
+        assert_eq!(
+            Err(MalType::error(
+                "unbalanced input: expected ']', got EOF".to_string()
+            )),
+            read_all("(def! v [1 {:a 2}")
+        );
+        assert!(read_all("{:a 1").is_err());
+        assert!(read_all("(str \"abc)").is_err());
+        assert!(read_all("1 2)").is_err());
+
+        let re = read_str("#\"[a-z]+\\\"\\d\"");
+        assert!(re.is_regex());
+        assert_eq!("[a-z]+\"\\d", re.get_regex().as_str());
+        assert!(read_str("#\"(\"").is_error());
+
+        assert_eq!(vec!["(", "\\(", "\\a", ")"], tokenizer("(\\( \\a)"));
+        assert_eq!(MalType::char('a'), read_str("\\a"));
+        assert_eq!(MalType::char('\n'), read_str("\\newline"));
+        assert_eq!(MalType::char(' '), read_str("\\space"));
+        assert_eq!(MalType::char('é'), read_str("\\é"));
+        assert_eq!(MalType::char('\u{1b}'), read_str("\\u001b"));
+        assert!(read_str("\\nope").is_error());
+    }
+
+    #[test]
+    fn is_incomplete_test() {
+        assert!(!is_incomplete(""));
+        assert!(!is_incomplete("(+ 1 2)"));
+        assert!(!is_incomplete("(+ 1 2))"));
+        assert!(!is_incomplete("\"a\\\\\""));
+        assert!(!is_incomplete("(str \";\") ; (comment"));
+        assert!(is_incomplete("(+ 1"));
+        assert!(is_incomplete("[1 {:a"));
+        assert!(is_incomplete("(str \"abc"));
+        assert!(is_incomplete("\"abc\\\""));
+        assert!(is_incomplete("#\"abc"));
     }
 }
@@ -0,0 +1,555 @@
+use cli::Options;
+use core;
+use eval;
+use eval::Environment;
+use highlight::MalHelper;
+use libc;
+use new_environment;
+use printer::{pprint_str, pr_str, pr_str_limited};
+use reader;
+use rustyline::error::ReadlineError;
+use rustyline::Config;
+use rustyline::Editor;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+use types::MalType;
+
+const CONTINUATION_PROMPT: &str = "  ... ";
+const HISTORY_SIZE: usize = 1000;
+
+const COMMANDS: &str = ":doc SYM           describe what SYM is bound to
+:source SYM        print the definition of a mal function or macro
+:env [PREFIX]      list the bindings in the root environment
+:load FILE         load FILE and print its last value
+:reset             start over with a fresh environment
+:time EXPR         evaluate EXPR and print how long it took
+:macroexpand EXPR  expand the macro call EXPR without evaluating it
+:help              print this list";
+
+const RESULT_VARS: [&str; 4] = ["*1", "*2", "*3", "*e"];
+
+const SPECIAL_FORMS: [&str; 19] = [
+    "def!",
+    "defmacro!",
+    "def-dynamic!",
+    "binding",
+    "with-open",
+    "let*",
+    "do",
+    "if",
+    "fn*",
+    "loop*",
+    "recur",
+    "quote",
+    "quasiquote",
+    "quasiquoteexpand",
+    "unquote",
+    "splice-unquote",
+    "macroexpand",
+    "macroexpand-1",
+    "macroexpand-all",
+];
+
+//$MAL_HISTORY, otherwise mal/history under $XDG_DATA_HOME or ~/.local/share
+pub fn default_history_file() -> Option<PathBuf> {
+    history_file_from(|name| env::var_os(name))
+}
+
+//The history file location given a way to look up environment variables
+fn history_file_from<F: Fn(&str) -> Option<OsString>>(var: F) -> Option<PathBuf> {
+    if let Some(f) = var("MAL_HISTORY") {
+        return Some(PathBuf::from(f));
+    }
+
+    let data_home = match var("XDG_DATA_HOME") {
+        Some(ref d) if !d.is_empty() => PathBuf::from(d),
+        _ => PathBuf::from(var("HOME")?).join(".local").join("share"),
+    };
+    Some(data_home.join("mal").join("history"))
+}
+
+fn push_space(entry: &mut String) {
+    if !entry.ends_with(' ') {
+        entry.push(' ');
+    }
+}
+
+//The history file keeps one entry per line, so a multi-line entry is folded onto one:
+//comments are dropped, whitespace runs collapse and newlines inside strings become \n escapes.
+pub fn history_entry(text: &str) -> String {
+    let mut entry = String::new();
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+
+    for c in text.trim().chars() {
+        if in_comment {
+            if c == '\n' {
+                in_comment = false;
+                push_space(&mut entry);
+            }
+        } else if in_string {
+            if c == '\n' {
+                entry.push_str("\\n");
+            } else {
+                entry.push(c);
+            }
+            in_string = !(c == '"' && !escaped);
+            escaped = c == '\\' && !escaped;
+        } else if c == ';' && !escaped {
+            in_comment = true;
+        } else if c.is_whitespace() {
+            push_space(&mut entry);
+        } else {
+            entry.push(c);
+            in_string = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+        }
+    }
+
+    entry.trim_end().to_string()
+}
+
+//Adds an entry unless it's blank or unreadable
+fn add_history(rl: &mut Editor<MalHelper>, entry: &str) {
+    if entry.is_empty() || reader::read_str(entry).is_error() {
+        return;
+    }
+    rl.add_history_entry(entry);
+}
+
+//Each entry keeps only its latest position, so repeats move to the end
+fn dedup_history<'a, I: Iterator<Item = &'a String>>(entries: I) -> Vec<String> {
+    let entries: Vec<&String> = entries.collect();
+    let mut seen = HashSet::new();
+    let mut kept: Vec<String> = entries
+        .into_iter()
+        .rev()
+        .filter(|e| seen.insert(*e))
+        .cloned()
+        .collect();
+    kept.reverse();
+    kept
+}
+
+//rustyline's history can't drop a single entry, so older copies go once, on saving
+fn save_history(rl: &mut Editor<MalHelper>, path: &PathBuf) {
+    let kept = dedup_history(rl.history().iter());
+    if kept.len() < rl.history().len() {
+        rl.clear_history();
+        for e in kept {
+            rl.add_history_entry(e);
+        }
+    }
+
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = rl.save_history(path) {
+        eprintln!(
+            "Warning: could not save history to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+//*1 *2 *3 hold the last three results and *e the last error
+fn init_result_vars(env: &Environment) {
+    for var in RESULT_VARS.iter() {
+        env.set(var, MalType::nil());
+    }
+}
+
+fn record_result(result: &MalType, env: &Environment) {
+    let root = env.get_root();
+    if result.is_error() {
+        root.set("*e", result.clone());
+    } else {
+        root.set("*3", root.get("*2"));
+        root.set("*2", root.get("*1"));
+        root.set("*1", result.clone());
+    }
+}
+
+//Evaluates an entry, remembering its result, and pretty prints it readably
+fn eval_entry(text: &str, env: &mut Environment, highlight: bool) -> String {
+    let result = eval_result(text, env);
+    show_result(&result, env, highlight)
+}
+
+fn eval_result(text: &str, env: &mut Environment) -> MalType {
+    let result = eval::eval(&reader::read_str(text), env);
+    record_result(&result, env);
+    result
+}
+
+fn show_result(result: &MalType, env: &Environment, highlight: bool) -> String {
+    let mut layout = core::print_layout(env);
+    layout.highlight = highlight;
+    pprint_str(result, true, &layout)
+}
+
+//What kind of value a binding holds, for :doc and :env
+fn kind(value: &MalType) -> &'static str {
+    if value.is_func() {
+        if value.is_macro() {
+            "builtin macro"
+        } else {
+            "builtin function"
+        }
+    } else if value.is_func_tco() {
+        if value.is_macro() {
+            "macro"
+        } else {
+            "function"
+        }
+    } else if value.is_atom() {
+        "atom"
+    } else if value.is_handle() {
+        "handle"
+    } else if value.is_list() {
+        "list"
+    } else if value.is_vector() {
+        "vector"
+    } else if value.is_map() {
+        "map"
+    } else {
+        "value"
+    }
+}
+
+fn doc(sym: &str, env: &Environment) -> String {
+    if SPECIAL_FORMS.contains(&sym) {
+        return format!("{}\n  special form", sym);
+    }
+
+    match env.find(sym) {
+        Some(ref v) if v.is_func_tco() => {
+            let params: Vec<String> = v
+                .get_func_tco()
+                .0
+                .into_iter()
+                .map(|a| pr_str(&MalType::list(a.params), true))
+                .collect();
+            format!("{}\n  {} {}", sym, kind(v), params.join(" "))
+        }
+        Some(ref v) if v.is_func() => format!("{}\n  {}", sym, kind(v)),
+        Some(ref v) => format!("{}\n  {}: {}", sym, kind(v), pr_str_limited(v, true)),
+        None => format!("{} not found.", sym),
+    }
+}
+
+fn source(sym: &str, env: &Environment) -> String {
+    match env.find(sym) {
+        Some(ref v) if v.is_func_tco() => {
+            let arities = v.get_func_tco().0;
+            let mut def = vec![MalType::symbol("fn*".to_string())];
+            if arities.len() == 1 {
+                def.push(MalType::list(arities[0].params.clone()));
+                def.push(arities[0].body.clone());
+            } else {
+                for a in arities {
+                    def.push(MalType::list(vec![MalType::vector(a.params), a.body]));
+                }
+            }
+            pr_str(&MalType::list(def), true)
+        }
+        Some(ref v) if v.is_func() => format!("{} is a {}, no source available.", sym, kind(v)),
+        Some(_) => format!("{} is not a function.", sym),
+        None => format!("{} not found.", sym),
+    }
+}
+
+fn list_env(prefix: &str, env: &Environment) -> String {
+    let root = env.get_root();
+    let map = root.map.borrow();
+    let mut names: Vec<&String> = map.keys().filter(|k| k.starts_with(prefix)).collect();
+    names.sort();
+
+    let lines: Vec<String> = names
+        .iter()
+        .map(|name| format!("{:<24} {}", name, kind(&map[*name])))
+        .collect();
+    lines.join("\n")
+}
+
+fn time(expr: &str, env: &mut Environment, highlight: bool) -> String {
+    let start = Instant::now();
+    let result = eval_entry(expr, env, highlight);
+    let elapsed = start.elapsed();
+    let msecs = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_nanos()) / 1e6;
+
+    format!("Elapsed time: {:.3} msecs\n{}", msecs, result)
+}
+
+//Runs a colon-command, returning None when the text isn't one so it gets evaluated
+fn run_command(
+    text: &str,
+    opts: &Options,
+    env: &mut Environment,
+    highlight: bool,
+) -> Option<String> {
+    let text = text.trim();
+    let (name, arg) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+
+    let needs_arg = [":doc", ":source", ":load", ":time", ":macroexpand"];
+    if arg.is_empty() && needs_arg.contains(&name) {
+        return Some(format!("{} needs an argument, see :help", name));
+    }
+
+    let output = match name {
+        ":doc" => doc(arg, env),
+        ":source" => source(arg, env),
+        ":env" => list_env(arg, env),
+        ":load" => pr_str_limited(&core::load_file(env, arg), true),
+        ":reset" => {
+            *env = new_environment(opts);
+            init_result_vars(env);
+            "Environment reset.".to_string()
+        }
+        ":time" => time(arg, env, highlight),
+        ":macroexpand" => pr_str_limited(&eval::macroexpand(&reader::read_str(arg), env), true),
+        ":help" => COMMANDS.to_string(),
+        _ => return None,
+    };
+    Some(output)
+}
+
+//Reads one complete form, prompting for more lines while brackets or strings are open
+fn read_entry(rl: &mut Editor<MalHelper>, prompt: &str) -> Result<String, ReadlineError> {
+    let mut text = rl.readline(prompt)?;
+
+    while reader::is_incomplete(&text) {
+        match rl.readline(CONTINUATION_PROMPT) {
+            Ok(line) => {
+                text.push('\n');
+                text.push_str(&line);
+            }
+            //CTRL-C abandons a partial entry without leaving the REPL
+            Err(ReadlineError::Interrupted) => return Ok(String::new()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(text)
+}
+
+//Colour is on unless turned off, and only when printing to a terminal
+fn use_colour(opts: &Options) -> bool {
+    opts.color
+        && env::var_os("NO_COLOR").is_none()
+        && unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
+}
+
+pub fn run_repl(opts: &Options, env: &mut Environment) {
+    let highlight = use_colour(opts);
+    let history_file = if opts.history {
+        match opts.history_file {
+            Some(ref f) => Some(PathBuf::from(f)),
+            None => default_history_file(),
+        }
+    } else {
+        None
+    };
+
+    let config = Config::builder()
+        .max_history_size(HISTORY_SIZE)
+        .history_ignore_dups(true)
+        .auto_add_history(false)
+        .build();
+    let mut rl = Editor::<MalHelper>::with_config(config);
+    if highlight {
+        rl.set_helper(Some(MalHelper));
+    }
+    if let Some(ref f) = history_file {
+        if rl.load_history(f).is_err() && !opts.quiet {
+            println!("No previous history.");
+        }
+    }
+
+    init_result_vars(env);
+    loop {
+        //the prompt names the namespace entries are evaluated in
+        let prompt = format!("{}> ", env.current_ns());
+        match read_entry(&mut rl, &prompt) {
+            Ok(text) => {
+                if text.trim().is_empty() {
+                    continue;
+                }
+                //entries whose evaluation failed aren't worth recalling
+                let (output, failed) = match run_command(&text, opts, env, highlight) {
+                    Some(output) => (output, false),
+                    None => {
+                        let result = eval_result(&text, env);
+                        (show_result(&result, env, highlight), result.is_error())
+                    }
+                };
+                if !failed {
+                    add_history(&mut rl, &history_entry(&text));
+                }
+                if core::exit_status().is_some() {
+                    break;
+                }
+                println!("{}", output);
+            }
+            Err(ReadlineError::Interrupted) => {
+                if !opts.quiet {
+                    println!("CTRL-C");
+                }
+                break;
+            }
+            Err(ReadlineError::Eof) => {
+                if !opts.quiet {
+                    println!("CTRL-D");
+                }
+                break;
+            }
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        }
+    }
+
+    if let Some(ref f) = history_file {
+        save_history(&mut rl, f);
+    }
+}
+
+/*
+  Unit Tests for various functions/methods
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rep;
+
+    #[test]
+    fn history_entry_test() {
+        assert_eq!("(+ 1 2)", history_entry("  (+ 1\n   2)\n"));
+        assert_eq!("(str \"a  b\")", history_entry("(str  \"a  b\")"));
+        assert_eq!("(def! a 1)", history_entry("(def! a ; the answer\n 1)"));
+        assert_eq!(
+            "(str \"a\\nb\" \";\")",
+            history_entry("(str \"a\nb\" \";\")")
+        );
+        assert_eq!("(str \"\\\"\" 1)", history_entry("(str \"\\\"\"\n1)"));
+        assert_eq!("\\; 1", history_entry("\\;\n1 ; done"));
+    }
+
+    #[test]
+    fn run_command_test() {
+        let opts = Options::new();
+        let mut env = new_environment(&opts);
+        rep("(def! answer 42)", &mut env);
+
+        let mut run = |text: &str| run_command(text, &opts, &mut env, false);
+        assert_eq!(Some("not\n  function (a)".to_string()), run(":doc not"));
+        assert_eq!(Some("cond\n  macro (& xs)".to_string()), run(":doc cond"));
+        assert_eq!(Some("+\n  builtin function".to_string()), run(":doc +"));
+        assert_eq!(Some("if\n  special form".to_string()), run(":doc if"));
+        assert_eq!(
+            Some("answer\n  value: 42".to_string()),
+            run(":doc  answer ")
+        );
+        assert_eq!(Some("nope not found.".to_string()), run(":doc nope"));
+        assert_eq!(
+            Some(":doc needs an argument, see :help".to_string()),
+            run(":doc")
+        );
+
+        assert_eq!(
+            Some("(fn* (a) (if a false true))".to_string()),
+            run(":source not")
+        );
+        assert_eq!(
+            Some("answer is not a function.".to_string()),
+            run(":source answer")
+        );
+
+        assert_eq!(
+            Some("answer                   value".to_string()),
+            run(":env ans")
+        );
+        assert_eq!(
+            Some("(if true 1 (cond))".to_string()),
+            run(":macroexpand (cond true 1)")
+        );
+        assert_eq!(
+            Some("3".to_string()),
+            run(":time (+ 1 2)").map(|o| o.lines().last().unwrap().to_string())
+        );
+        assert_eq!(
+            Some("\"mal_tests/incD.mal\"".to_string()),
+            run(":load mal_tests/incD.mal")
+        );
+
+        assert_eq!(None, run(":keyword"));
+        assert_eq!(None, run("(+ 1 2)"));
+
+        assert_eq!(Some("Environment reset.".to_string()), run(":reset"));
+        assert_eq!(Some("answer not found.".to_string()), run(":doc answer"));
+    }
+
+    #[test]
+    fn result_vars_test() {
+        let mut env = new_environment(&Options::new());
+        init_result_vars(&env);
+        assert_eq!("nil", rep("*1", &mut env));
+
+        eval_entry("(+ 1 2)", &mut env, false);
+        eval_entry("(str \"a\")", &mut env, false);
+        eval_entry("(nope)", &mut env, false);
+        eval_entry("[1 2]", &mut env, false);
+        assert_eq!("(3 \"a\" [1 2])", rep("(list *3 *2 *1)", &mut env));
+        assert_eq!(MalType::error("nope not found.".to_string()), env.get("*e"));
+
+        assert_eq!("\"a\"", eval_entry("*2", &mut env, false));
+        assert_eq!("(\"a\" [1 2] \"a\")", rep("(list *3 *2 *1)", &mut env));
+    }
+
+    #[test]
+    fn default_history_file_test() {
+        let vars = |set: Vec<(&'static str, &'static str)>| {
+            move |name: &str| {
+                set.iter()
+                    .find(|v| v.0 == name)
+                    .map(|v| OsString::from(v.1))
+            }
+        };
+
+        assert_eq!(
+            Some(PathBuf::from("/tmp/mal-history")),
+            history_file_from(vars(vec![
+                ("MAL_HISTORY", "/tmp/mal-history"),
+                ("XDG_DATA_HOME", "/tmp/xdg"),
+            ]))
+        );
+        assert_eq!(
+            Some(PathBuf::from("/tmp/xdg/mal/history")),
+            history_file_from(vars(vec![("XDG_DATA_HOME", "/tmp/xdg"), ("HOME", "/h")]))
+        );
+        assert_eq!(
+            Some(PathBuf::from("/h/.local/share/mal/history")),
+            history_file_from(vars(vec![("XDG_DATA_HOME", ""), ("HOME", "/h")]))
+        );
+        assert_eq!(None, history_file_from(vars(vec![])));
+    }
+
+    #[test]
+    fn dedup_history_test() {
+        let entries: Vec<String> = vec!["a", "b", "a", "c", "b"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(vec!["a", "c", "b"], dedup_history(entries.iter()));
+    }
+}
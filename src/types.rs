@@ -1,5 +1,8 @@
+use regex::Regex;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::io;
 use std::rc::Rc;
 
 use eval::Environment;
@@ -15,6 +18,7 @@ pub enum MalEnum {
     Int(i64),
     Float(f64),
     Bool(bool),
+    Char(char),
     Str(Rc<String>),
     Symbol(Rc<String>),
     KeyWord(Rc<String>),
@@ -22,14 +26,10 @@ pub enum MalEnum {
     List(Rc<Vec<MalType>>),
     Vector(Rc<Vec<MalType>>),
     Map(Rc<Vec<MalType>>),
+    Regex(MalRegex),
+    Handle(Rc<MalHandle>),
     Func(Rc<Box<BuiltinFunc>>, bool),
-    TCOFunc(
-        Vec<MalType>,
-        Box<MalType>,
-        Environment,
-        Rc<Box<BuiltinFunc>>,
-        bool,
-    ),
+    TCOFunc(Vec<Arity>, Environment, Rc<Box<BuiltinFunc>>, bool),
     Error(Rc<String>),
 }
 
@@ -48,6 +48,125 @@ impl PartialEq for BuiltinFunc {
     }
 }
 
+//Compiled regular expressions, compared by their source pattern
+#[derive(Debug, Clone)]
+pub struct MalRegex(pub Rc<Regex>);
+
+impl PartialEq for MalRegex {
+    fn eq(&self, other: &MalRegex) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+//Patterns built at runtime can be endless, so the cache starts over once it holds this many
+const REGEX_CACHE_SIZE: usize = 256;
+
+thread_local! {
+    static REGEX_CACHE: RefCell<HashMap<String, Rc<Regex>>> = RefCell::new(HashMap::new());
+}
+
+//Compile a pattern, reusing an earlier compilation of the same pattern if there is one
+pub fn compile_regex(pattern: &str) -> Result<Rc<Regex>, String> {
+    if let Some(re) = REGEX_CACHE.with(|c| c.borrow().get(pattern).cloned()) {
+        return Ok(re);
+    }
+
+    match Regex::new(pattern) {
+        Ok(re) => {
+            let re = Rc::new(re);
+            REGEX_CACHE.with(|c| {
+                let mut cache = c.borrow_mut();
+                if cache.len() >= REGEX_CACHE_SIZE {
+                    cache.clear();
+                }
+                cache.insert(pattern.to_string(), re.clone())
+            });
+            Ok(re)
+        }
+        Err(e) => Err(format!("invalid regex {}: {}", pattern, e)),
+    }
+}
+
+//One parameter list of a mal function and the body it evaluates
+#[derive(Debug, PartialEq, Clone)]
+pub struct Arity {
+    pub params: Vec<MalType>,
+    pub body: MalType,
+}
+
+impl Arity {
+    //The number of positional parameters, and whether a & rest parameter follows them
+    pub fn required(&self) -> (usize, bool) {
+        let mut count = 0;
+        let mut i = 0;
+        while i < self.params.len() {
+            let p = &self.params[i];
+            if p.is_symbol() && *p.get_string() == "&" {
+                return (count, true);
+            } else if p.is_keyword() && *p.get_string() == ":as" {
+                i += 2;
+            } else {
+                count += 1;
+                i += 1;
+            }
+        }
+        (count, false)
+    }
+
+    pub fn accepts(&self, args: usize) -> bool {
+        match self.required() {
+            (n, true) => args >= n,
+            (n, false) => args == n,
+        }
+    }
+}
+
+pub enum HandleStream {
+    Input(Box<dyn io::BufRead>),
+    Output(Box<dyn io::Write>),
+    Closed,
+}
+
+//Buffered reader or writer over a file or one of the standard streams
+pub struct MalHandle {
+    pub name: String,
+    pub stream: RefCell<HandleStream>,
+}
+
+impl MalHandle {
+    pub fn input(name: String, r: Box<dyn io::BufRead>) -> MalHandle {
+        MalHandle {
+            name,
+            stream: RefCell::new(HandleStream::Input(r)),
+        }
+    }
+    pub fn output(name: String, w: Box<dyn io::Write>) -> MalHandle {
+        MalHandle {
+            name,
+            stream: RefCell::new(HandleStream::Output(w)),
+        }
+    }
+    pub fn is_closed(&self) -> bool {
+        match *self.stream.borrow() {
+            HandleStream::Closed => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for MalHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MalHandle({})", self.name)
+    }
+}
+
+//Handles are only equal to themselves
+impl PartialEq for MalHandle {
+    fn eq(&self, other: &MalHandle) -> bool {
+        self as *const MalHandle == other as *const MalHandle
+    }
+}
+
 impl MalType {
     pub fn nil() -> MalType {
         MalType {
@@ -69,6 +188,11 @@ impl MalType {
             val: Rc::new(RefCell::new(MalEnum::Bool(val))),
         }
     }
+    pub fn char(val: char) -> MalType {
+        MalType {
+            val: Rc::new(RefCell::new(MalEnum::Char(val))),
+        }
+    }
     pub fn string(val: String) -> MalType {
         MalType {
             val: Rc::new(RefCell::new(MalEnum::Str(Rc::new(val)))),
@@ -104,22 +228,29 @@ impl MalType {
             val: Rc::new(RefCell::new(MalEnum::Map(Rc::new(val)))),
         }
     }
+    pub fn regex(val: Rc<Regex>) -> MalType {
+        MalType {
+            val: Rc::new(RefCell::new(MalEnum::Regex(MalRegex(val)))),
+        }
+    }
+    pub fn handle(val: MalHandle) -> MalType {
+        MalType {
+            val: Rc::new(RefCell::new(MalEnum::Handle(Rc::new(val)))),
+        }
+    }
     pub fn func(f: Rc<Box<BuiltinFunc>>, is_macro: bool) -> MalType {
         MalType {
             val: Rc::new(RefCell::new(MalEnum::Func(f, is_macro))),
         }
     }
     pub fn func_tco(
-        args: Vec<MalType>,
-        body: Box<MalType>,
+        arities: Vec<Arity>,
         env: Environment,
         func: Rc<Box<BuiltinFunc>>,
         is_macro: bool,
     ) -> MalType {
         MalType {
-            val: Rc::new(RefCell::new(MalEnum::TCOFunc(
-                args, body, env, func, is_macro,
-            ))),
+            val: Rc::new(RefCell::new(MalEnum::TCOFunc(arities, env, func, is_macro))),
         }
     }
     pub fn error(val: String) -> MalType {
@@ -151,6 +282,12 @@ impl MalType {
             _ => false,
         }
     }
+    pub fn is_char(&self) -> bool {
+        match *self.val.borrow() {
+            MalEnum::Char(_) => true,
+            _ => false,
+        }
+    }
     pub fn is_string(&self) -> bool {
         match *self.val.borrow() {
             MalEnum::Str(_) => true,
@@ -193,6 +330,18 @@ impl MalType {
             _ => false,
         }
     }
+    pub fn is_regex(&self) -> bool {
+        match *self.val.borrow() {
+            MalEnum::Regex(_) => true,
+            _ => false,
+        }
+    }
+    pub fn is_handle(&self) -> bool {
+        match *self.val.borrow() {
+            MalEnum::Handle(_) => true,
+            _ => false,
+        }
+    }
     pub fn is_func(&self) -> bool {
         match *self.val.borrow() {
             MalEnum::Func(_, _) => true,
@@ -201,14 +350,14 @@ impl MalType {
     }
     pub fn is_func_tco(&self) -> bool {
         match *self.val.borrow() {
-            MalEnum::TCOFunc(_, _, _, _, _) => true,
+            MalEnum::TCOFunc(_, _, _, _) => true,
             _ => false,
         }
     }
     pub fn is_macro(&self) -> bool {
         match *self.val.borrow() {
             MalEnum::Func(_, is_macro) => is_macro,
-            MalEnum::TCOFunc(_, _, _, _, is_macro) => is_macro,
+            MalEnum::TCOFunc(_, _, _, is_macro) => is_macro,
             _ => false,
         }
     }
@@ -238,6 +387,12 @@ impl MalType {
             _ => panic!(),
         }
     }
+    pub fn get_char(&self) -> char {
+        match *self.val.borrow() {
+            MalEnum::Char(c) => c,
+            _ => panic!(),
+        }
+    }
     pub fn get_string(&self) -> Rc<String> {
         let val = self.val.borrow();
         match *val {
@@ -263,23 +418,27 @@ impl MalType {
             _ => panic!(),
         }
     }
+    pub fn get_regex(&self) -> Rc<Regex> {
+        match *self.val.borrow() {
+            MalEnum::Regex(ref r) => r.0.clone(),
+            _ => panic!(),
+        }
+    }
+    pub fn get_handle(&self) -> Rc<MalHandle> {
+        match *self.val.borrow() {
+            MalEnum::Handle(ref h) => h.clone(),
+            _ => panic!(),
+        }
+    }
     pub fn get_func(&self) -> (Rc<Box<BuiltinFunc>>, bool) {
         match *self.val.borrow() {
             MalEnum::Func(ref f, ref is_macro) => (f.clone(), is_macro.clone()),
             _ => panic!(),
         }
     }
-    pub fn get_func_tco(
-        &self,
-    ) -> (
-        Vec<MalType>,
-        Box<MalType>,
-        Environment,
-        Rc<Box<BuiltinFunc>>,
-        bool,
-    ) {
+    pub fn get_func_tco(&self) -> (Vec<Arity>, Environment, Rc<Box<BuiltinFunc>>, bool) {
         match self.val.borrow().clone() {
-            MalEnum::TCOFunc(a, b, c, f, is_macro) => (a, b, c, f, is_macro),
+            MalEnum::TCOFunc(a, e, f, is_macro) => (a, e, f, is_macro),
             _ => panic!(),
         }
     }
@@ -287,7 +446,7 @@ impl MalType {
         let temp = &mut *self.val.borrow_mut();
         if let MalEnum::Func(_, ref mut is_macro) = temp {
             *is_macro = val;
-        } else if let MalEnum::TCOFunc(_, _, _, _, ref mut is_macro) = temp {
+        } else if let MalEnum::TCOFunc(_, _, _, ref mut is_macro) = temp {
             *is_macro = val;
         }
     }
@@ -305,6 +464,7 @@ impl Clone for MalEnum {
             MalEnum::Int(i) => MalEnum::Int(*i),
             MalEnum::Float(f) => MalEnum::Float(*f),
             MalEnum::Bool(b) => MalEnum::Bool(*b),
+            MalEnum::Char(c) => MalEnum::Char(*c),
             MalEnum::Str(s) => MalEnum::Str(s.clone()),
             MalEnum::Symbol(s) => MalEnum::Symbol(s.clone()),
             MalEnum::KeyWord(s) => MalEnum::KeyWord(s.clone()),
@@ -312,15 +472,13 @@ impl Clone for MalEnum {
             MalEnum::List(l) => MalEnum::List(l.clone()),
             MalEnum::Vector(l) => MalEnum::Vector(l.clone()),
             MalEnum::Map(l) => MalEnum::Map(l.clone()),
+            MalEnum::Regex(r) => MalEnum::Regex(r.clone()),
+            MalEnum::Handle(h) => MalEnum::Handle(h.clone()),
             MalEnum::Func(f, is_macro) => MalEnum::Func(f.clone(), *is_macro),
             MalEnum::Error(s) => MalEnum::Error(s.clone()),
-            MalEnum::TCOFunc(args, body, env, func, is_macro) => MalEnum::TCOFunc(
-                args.clone(),
-                body.clone(),
-                env.clone(),
-                func.clone(),
-                *is_macro,
-            ),
+            MalEnum::TCOFunc(arities, env, func, is_macro) => {
+                MalEnum::TCOFunc(arities.clone(), env.clone(), func.clone(), *is_macro)
+            }
         }
     }
 }